@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::error::Error;
+
+/// A token shared by the operations of a run, which stop as soon as the run is cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+    /// The tokens cancelled together with this token.
+    children: Mutex<Vec<CancellationToken>>,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+        for child in self.inner.children.lock().unwrap().drain(..) {
+            child.cancel();
+        }
+    }
+
+    /// Creates a token which is cancelled when this token is, but can also be cancelled on its own.
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::default();
+        let mut children = self.inner.children.lock().unwrap();
+        // The children nobody but this token holds any longer are dropped.
+        children.retain(|token| Arc::strong_count(&token.inner) > 1);
+        children.push(child.clone());
+        drop(children);
+
+        // The flag is checked after registering the child, so a concurrent `cancel` is not missed.
+        if self.is_cancelled() {
+            child.cancel();
+        }
+        child
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `Error::Cancelled` if the token is cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // The notification is registered before checking the flag, so a concurrent `cancel` is not missed.
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Runs the future until it completes, failing with `Error::Cancelled` if the run is cancelled first.
+pub async fn cancellable<F: Future>(cancellation: Option<&CancellationToken>, future: F) -> Result<F::Output, Error> {
+    let Some(cancellation) = cancellation else {
+        return Ok(future.await);
+    };
+
+    cancellation.check()?;
+    tokio::select! {
+        output = future => Ok(output),
+        _ = cancellation.cancelled() => Err(Error::Cancelled),
+    }
+}
+
+/// The key of a cancellable run.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RunKey {
+    /// A flow run, keyed by the id of its run record.
+    Flow(i64),
+    /// A streamed command, keyed by the id chosen by the window.
+    Command(String),
+}
+
+/// The cancellation tokens of the runs in progress.
+#[derive(Debug, Default)]
+pub struct RunCancellations {
+    tokens: Mutex<HashMap<RunKey, CancellationToken>>,
+}
+
+impl RunCancellations {
+    /// Registers a run, returning its cancellation token.
+    pub fn register(&self, key: RunKey) -> CancellationToken {
+        let token = CancellationToken::default();
+        self.tokens.lock().unwrap().insert(key, token.clone());
+        token
+    }
+
+    /// Removes a finished run.
+    pub fn remove(&self, key: &RunKey) {
+        self.tokens.lock().unwrap().remove(key);
+    }
+
+    /// Checks whether the run is in progress.
+    pub fn is_running(&self, key: &RunKey) -> bool {
+        self.tokens.lock().unwrap().contains_key(key)
+    }
+
+    /// Cancels a run in progress.
+    pub fn cancel(&self, key: &RunKey) -> Result<(), Error> {
+        let tokens = self.tokens.lock().unwrap();
+        let token = tokens.get(key).ok_or_else(|| Error::RunNotInProgress(format!("{:?}", key)))?;
+        token.cancel();
+        Ok(())
+    }
+
+    /// Cancels all runs in progress.
+    pub fn cancel_all(&self) {
+        for token in self.tokens.lock().unwrap().values() {
+            token.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancels_children_with_parent() {
+        let parent = CancellationToken::default();
+        let child = parent.child();
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(parent.child().is_cancelled());
+    }
+
+    #[test]
+    fn cancels_child_without_parent() {
+        let parent = CancellationToken::default();
+        let child = parent.child();
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+}
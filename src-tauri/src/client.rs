@@ -2,17 +2,38 @@ use async_trait::async_trait;
 use russh::client::Handler;
 use russh::keys::key;
 
-pub struct Client;
+use crate::error::Error;
+use crate::known_hosts::KnownHosts;
+
+pub struct Client {
+    server_id: String,
+    host: String,
+    port: u16,
+    known_hosts: KnownHosts,
+}
+
+impl Client {
+    pub fn new(server_id: impl Into<String>, host: impl Into<String>, port: u16, known_hosts: KnownHosts) -> Self {
+        Self { server_id: server_id.into(), host: host.into(), port, known_hosts }
+    }
+}
 
 #[async_trait]
 impl Handler for Client {
-    type Error = russh::Error;
+    type Error = Error;
 
-    #[allow(unused_variables)]
+    /// Verifies the server key against the known hosts store (trust on first use).
     async fn check_server_key(
         &mut self,
         server_public_key: &key::PublicKey,
     ) -> Result<bool, Self::Error> {
+        self.known_hosts.verify(
+            &self.server_id,
+            &self.host,
+            self.port,
+            server_public_key.name(),
+            &server_public_key.fingerprint(),
+        ).await?;
         Ok(true)
     }
 }
@@ -1,45 +1,160 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::LazyLock;
 
+use russh::client::Prompt;
 use serde_json::Value;
-use tauri::Window;
+use tauri::{State, Window};
 
+use crate::cancellation::{cancellable, RunCancellations, RunKey};
+use crate::client::Client;
 use crate::error::Error;
-use crate::flow::{FlowDefinition, FlowEngine};
+use crate::flow::{FlowDefinition, FlowEngine, FlowPlan, FlowRunOptions};
+use crate::flow_report::{FlowReporter, FlowRunStartedEvent, FlowRunSummary};
 use crate::hospital_settings::HospitalSettings;
-use crate::server_settings::ServerSettings;
-use crate::session::Session;
+use crate::interactive_auth::InteractivePrompter;
+use crate::known_hosts::{KnownHost, KnownHosts};
+use crate::pending_requests::Confirmations;
+use crate::run_history::{RunHistory, RunHistoryFilter, RunRecord};
+use crate::server_settings::{AuthMethod, ServerSettings};
+use crate::session::{FileBackup, Session};
+use crate::session_pool::SessionPool;
 
 static FLOW_ENGINE: LazyLock<FlowEngine> = LazyLock::new(|| FlowEngine::new());
 
-async fn create_session(server_settings: ServerSettings) -> Result<Session, Error> {
-    let session = Session::connect(
-        server_settings.username,
-        server_settings.password,
-        (server_settings.host, server_settings.port),
-    ).await?;
+async fn create_session(server_settings: &ServerSettings, known_hosts: &KnownHosts, prompter: &InteractivePrompter, window: &Window) -> Result<Session, Error> {
+    let mut jump_session: Option<Session> = None;
+    for jump_host in &server_settings.jump_hosts {
+        let client = Client::new(jump_host.id.as_str(), jump_host.host.as_str(), jump_host.port, known_hosts.clone());
+        let mut session = match jump_session.take() {
+            Some(jump) => Session::connect_via(jump, &jump_host.host, jump_host.port, client).await?,
+            None => Session::connect((jump_host.host.as_str(), jump_host.port), client).await?,
+        };
+        authenticate(&mut session, &jump_host.username, jump_host.auth_method(), prompter, window).await?;
+        jump_session = Some(session);
+    }
+
+    let client = Client::new(
+        server_settings.id.as_str(),
+        server_settings.host.as_str(),
+        server_settings.port,
+        known_hosts.clone(),
+    );
+    let mut session = match jump_session {
+        Some(jump) => Session::connect_via(jump, &server_settings.host, server_settings.port, client).await?,
+        None => Session::connect((server_settings.host.as_str(), server_settings.port), client).await?,
+    };
+    authenticate(&mut session, &server_settings.username, server_settings.auth_method(), prompter, window).await?;
+    if let Some(transfer_options) = server_settings.transfer_options {
+        session.set_transfer_options(transfer_options);
+    }
+
     Ok(session)
 }
 
+/// Acquires a session on the pooled connection of the server, connecting a new one if needed.
+async fn acquire_session(server_settings: &ServerSettings, known_hosts: &KnownHosts, prompter: &InteractivePrompter, session_pool: &SessionPool, window: &Window) -> Result<Session, Error> {
+    session_pool.acquire(server_settings, || create_session(server_settings, known_hosts, prompter, window)).await
+}
+
+/// Authenticates the session with the matching auth call of the auth method.
+/// If the server rejects the method, keyboard-interactive is tried, which is the only method of servers
+/// asking for a one-time password, the password of the password method answers its password prompts.
+async fn authenticate(session: &mut Session, username: &str, auth_method: AuthMethod, prompter: &InteractivePrompter, window: &Window) -> Result<(), Error> {
+    let result = match auth_method {
+        AuthMethod::Password { ref password } => {
+            session.authenticate_password(username, password.as_str()).await
+        }
+        AuthMethod::PrivateKey { ref private_key_file, ref passphrase } => {
+            session.authenticate_private_key(username, private_key_file, passphrase.as_deref()).await
+        }
+        AuthMethod::Agent { ref socket } => {
+            session.authenticate_agent(username, socket.as_deref()).await
+        }
+    };
+
+    match result {
+        Err(e @ (Error::AuthenticationFailed | Error::PrivateKeyRejected)) => {
+            let password = match auth_method {
+                AuthMethod::Password { ref password } => Some(password.as_str()),
+                _ => None,
+            };
+            let answer = move |name: String, instructions: String, prompts: Vec<Prompt>| async move {
+                prompter.ask(&name, &instructions, &prompts, password, window).await
+            };
+            match session.authenticate_keyboard_interactive(username, answer).await {
+                // The rejection of the configured method tells more than the one of the fallback.
+                Err(Error::AuthenticationFailed) => Err(e),
+                result => result,
+            }
+        }
+        result => result,
+    }
+}
+
+/// Runs the flow recording it in the run history, the run can be cancelled with its record id
+/// which is sent to the window in the `flow-run-started` event.
+/// The run is registered before the session is acquired, so a hanging connection or an unanswered
+/// host key or authentication prompt can be cancelled as well.
+async fn run_recorded_flow(server_settings: &ServerSettings, hospital_settings: HospitalSettings, flow_name: &str, args: HashMap<String, Value>, options: &FlowRunOptions, window: &Window, known_hosts: &KnownHosts, prompter: &InteractivePrompter, session_pool: &SessionPool, run_history: &RunHistory, cancellations: &RunCancellations) -> Result<FlowRunSummary, Error> {
+    let run_id = run_history.start_run(flow_name, server_settings, &hospital_settings, &args)?;
+    let key = RunKey::Flow(run_id);
+    let cancellation = cancellations.register(key.clone());
+    if let Err(e) = window.emit("flow-run-started", FlowRunStartedEvent { run_id, flow_name }) {
+        println!("Failed to emit flow run start: {:?}", e);
+    }
+
+    let session = cancellable(Some(&cancellation), acquire_session(server_settings, known_hosts, prompter, session_pool, window)).await;
+    let (summary, result) = match session.and_then(|session| session) {
+        Ok(mut session) => {
+            session.set_cancellation(Some(cancellation));
+            FLOW_ENGINE.run_flow(flow_name, hospital_settings, args, options, Some(&run_history.checkpoint(run_id)), &mut session, window).await
+        }
+        Err(e) => (FlowReporter::new(flow_name, window).finish(Some(&e)), Err(e)),
+    };
+    cancellations.remove(&key);
+    run_history.finish_run(run_id, &summary)?;
+
+    result.map(|_| summary)
+}
+
 #[tauri::command]
-pub async fn test_ssh_connection(server_settings: ServerSettings) -> Result<String, Error> {
-    let mut session = create_session(server_settings).await?;
+pub async fn test_ssh_connection(server_settings: ServerSettings, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<String, Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
     let result = session.execute_command("cat /etc/os-release | grep PRETTY_NAME | cut -d '=' -f 2 | tr -d '\"'").await?;
     Ok(result)
 }
 
 #[tauri::command]
-pub async fn execute_command(server_settings: ServerSettings, command: String) -> Result<String, Error> {
-    let mut session = create_session(server_settings).await?;
+pub async fn execute_command(server_settings: ServerSettings, command: String, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<String, Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
     let result = session.execute_command(&command).await?;
     Ok(result)
 }
 
 #[tauri::command]
-pub async fn execute_command_stream(server_settings: ServerSettings, command: String, window: Window) -> Result<(), Error> {
-    let mut session = create_session(server_settings).await?;
-    session.execute_command_stream(&command, &window).await?;
-    Ok(())
+pub async fn execute_command_stream(server_settings: ServerSettings, command: String, command_id: Option<String>, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>, cancellations: State<'_, RunCancellations>) -> Result<(), Error> {
+    let key = command_id.map(RunKey::Command);
+    let cancellation = key.clone().map(|key| cancellations.register(key));
+    let session = cancellable(cancellation.as_ref(), acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window)).await;
+    let result = match session.and_then(|session| session) {
+        Ok(mut session) => {
+            session.set_cancellation(cancellation);
+            session.execute_command_stream(&command, &window).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Some(ref key) = key {
+        cancellations.remove(key);
+    }
+
+    result
+}
+
+/// Cancels the streamed command started with the command id.
+#[tauri::command]
+pub fn cancel_command(command_id: String, cancellations: State<'_, RunCancellations>) -> Result<(), Error> {
+    cancellations.cancel(&RunKey::Command(command_id))
 }
 
 #[tauri::command]
@@ -48,7 +163,112 @@ pub fn list_flows() -> &'static [FlowDefinition] {
 }
 
 #[tauri::command]
-pub async fn execute_flow(server_settings: ServerSettings, hospital_settings: HospitalSettings, flow_name: String, args: Option<HashMap<String, Value>>, window: Window) -> Result<(), Error> {
-    let mut session = create_session(server_settings).await?;
-    FLOW_ENGINE.run_flow(&flow_name, hospital_settings, args.unwrap_or(HashMap::new()), &mut session, &window).await
+pub async fn execute_flow(server_settings: ServerSettings, hospital_settings: HospitalSettings, flow_name: String, args: Option<HashMap<String, Value>>, options: Option<FlowRunOptions>, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>, run_history: State<'_, RunHistory>, cancellations: State<'_, RunCancellations>) -> Result<FlowRunSummary, Error> {
+    let args = args.unwrap_or(HashMap::new());
+    run_recorded_flow(&server_settings, hospital_settings, &flow_name, args, &options.unwrap_or_default(), &window, &known_hosts, &prompter, &session_pool, &run_history, &cancellations).await
+}
+
+/// Resumes the latest run of the flow on the server with its hospital settings and args,
+/// at the given step index or at the step the run failed.
+#[tauri::command]
+pub async fn resume_flow(server_settings: ServerSettings, flow_name: String, start_index: Option<usize>, options: Option<FlowRunOptions>, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>, run_history: State<'_, RunHistory>, cancellations: State<'_, RunCancellations>) -> Result<FlowRunSummary, Error> {
+    let record = run_history.latest_run(&server_settings.id, &flow_name)?
+        .ok_or(Error::FlowExecutionFailed(format!("No run of flow {} to resume", flow_name)))?;
+    if !record.is_resumable() {
+        return Err(Error::FlowExecutionFailed(format!("Nothing to resume, the latest run of flow {} succeeded", flow_name)));
+    }
+    if cancellations.is_running(&RunKey::Flow(record.id)) {
+        return Err(Error::FlowExecutionFailed(format!("The latest run of flow {} is still running", flow_name)));
+    }
+    let hospital_settings: HospitalSettings = serde_json::from_value(record.hospital_settings.clone()).map_err(std::io::Error::from)?;
+    let args: HashMap<String, Value> = serde_json::from_value(record.args.clone()).map_err(std::io::Error::from)?;
+    let mut options = options.unwrap_or_default();
+    let start_index = start_index.unwrap_or_else(|| record.resume_index());
+    options.start_index = Some(start_index);
+    for (name, value) in record.variables(start_index) {
+        options.variables.entry(name).or_insert(value);
+    }
+    println!("Resume flow {} of run {} at step {:?}", flow_name, record.id, options.start_index);
+
+    run_recorded_flow(&server_settings, hospital_settings, &flow_name, args, &options, &window, &known_hosts, &prompter, &session_pool, &run_history, &cancellations).await
+}
+
+/// Cancels the flow run in progress, aborting its current command or transfer.
+#[tauri::command]
+pub fn cancel_flow(run_id: i64, cancellations: State<'_, RunCancellations>) -> Result<(), Error> {
+    cancellations.cancel(&RunKey::Flow(run_id))
+}
+
+#[tauri::command]
+pub fn list_run_history(filter: Option<RunHistoryFilter>, run_history: State<'_, RunHistory>) -> Result<Vec<RunRecord>, Error> {
+    run_history.query(&filter.unwrap_or_default())
+}
+
+#[tauri::command]
+pub fn get_run_record(id: i64, run_history: State<'_, RunHistory>) -> Result<RunRecord, Error> {
+    run_history.get(id)?.ok_or(Error::RunRecordNotFound(id))
+}
+
+#[tauri::command]
+pub fn export_run_history(filter: Option<RunHistoryFilter>, file: String, run_history: State<'_, RunHistory>) -> Result<(), Error> {
+    run_history.export(&filter.unwrap_or_default(), Path::new(&file))
+}
+
+#[tauri::command]
+pub async fn plan_flow(server_settings: ServerSettings, hospital_settings: HospitalSettings, flow_name: String, args: Option<HashMap<String, Value>>, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<FlowPlan, Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
+    FLOW_ENGINE.plan_flow(&flow_name, hospital_settings, args.unwrap_or(HashMap::new()), &mut session, &window).await
+}
+
+#[tauri::command]
+pub fn confirm_config_changes(request_id: u64, confirmed: bool, confirmations: State<'_, Confirmations>) -> Result<(), Error> {
+    if confirmations.respond(request_id, confirmed) {
+        Ok(())
+    } else {
+        Err(Error::ConfirmationNotFound(request_id))
+    }
+}
+
+#[tauri::command]
+pub async fn fetch_file(server_settings: ServerSettings, hospital_settings: HospitalSettings, source_file: String, target_file: Option<String>, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<String, Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
+    let local_file = FLOW_ENGINE.fetch_file(&hospital_settings.id, &source_file, target_file.as_deref(), &mut session, &window).await?;
+    local_file.to_str().map(str::to_owned).ok_or(Error::InvalidPath)
+}
+
+#[tauri::command]
+pub async fn list_config_backups(server_settings: ServerSettings, file: String, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<Vec<FileBackup>, Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
+    session.list_backups(&file).await
+}
+
+#[tauri::command]
+pub async fn restore_config_backup(server_settings: ServerSettings, file: String, backup_file: String, window: Window, known_hosts: State<'_, KnownHosts>, prompter: State<'_, InteractivePrompter>, session_pool: State<'_, SessionPool>) -> Result<(), Error> {
+    let mut session = acquire_session(&server_settings, &known_hosts, &prompter, &session_pool, &window).await?;
+    session.restore_backup(&file, &backup_file).await
+}
+
+#[tauri::command]
+pub async fn list_known_hosts(known_hosts: State<'_, KnownHosts>) -> Result<Vec<KnownHost>, Error> {
+    Ok(known_hosts.list().await)
+}
+
+#[tauri::command]
+pub async fn get_pending_host_key(server_id: String, known_hosts: State<'_, KnownHosts>) -> Result<Option<KnownHost>, Error> {
+    Ok(known_hosts.get_pending(&server_id).await)
+}
+
+#[tauri::command]
+pub async fn accept_host_key(server_id: String, fingerprint: String, known_hosts: State<'_, KnownHosts>) -> Result<(), Error> {
+    known_hosts.accept(&server_id, &fingerprint).await
+}
+
+#[tauri::command]
+pub async fn revoke_host_key(server_id: String, known_hosts: State<'_, KnownHosts>) -> Result<(), Error> {
+    known_hosts.revoke(&server_id).await
+}
+
+#[tauri::command]
+pub fn respond_keyboard_interactive(request_id: u64, responses: Option<Vec<String>>, prompter: State<'_, InteractivePrompter>) -> Result<(), Error> {
+    prompter.respond(request_id, responses)
 }
\ No newline at end of file
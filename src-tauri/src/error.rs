@@ -22,10 +22,33 @@ pub enum Error {
     #[error(transparent)]
     TemplateError(#[from] tera::Error),
 
+    #[error(transparent)]
+    DatabaseError(#[from] rusqlite::Error),
+
     /// The server authentication failed.
     #[error("服务器认证失败")]
     AuthenticationFailed,
 
+    /// The interactive authentication was cancelled or not answered in time.
+    #[error("认证已取消")]
+    AuthenticationCancelled,
+
+    /// The private key was rejected by the server.
+    #[error("服务器拒绝了该私钥")]
+    PrivateKeyRejected,
+
+    /// The passphrase of the private key is missing or wrong.
+    #[error("私钥密码错误")]
+    InvalidPassphrase,
+
+    /// The private key file could not be loaded.
+    #[error("无法读取私钥: {0}")]
+    InvalidPrivateKey(String),
+
+    /// The ssh-agent could not be used.
+    #[error("无法使用 ssh-agent: {0}")]
+    AgentUnavailable(String),
+
     /// The command execution timed out.
     #[error("命令执行超时")]
     CommandExecutionTimeout,
@@ -38,6 +61,26 @@ pub enum Error {
     #[error("流程执行失败: {0}")]
     FlowExecutionFailed(String),
 
+    /// The operator rejected or did not confirm the changes of a config file.
+    #[error("配置文件变更未被确认: {0}")]
+    ConfigChangeRejected(String),
+
+    /// No confirmation request is waiting for the answer.
+    #[error("未找到待确认的请求: {0}")]
+    ConfirmationNotFound(u64),
+
+    /// The run was cancelled by the operator.
+    #[error("执行已取消")]
+    Cancelled,
+
+    /// No run in progress matches the id.
+    #[error("未找到正在执行的任务: {0}")]
+    RunNotInProgress(String),
+
+    /// The run record does not exist.
+    #[error("未找到执行记录: {0}")]
+    RunRecordNotFound(i64),
+
     /// The path is invalid.
     #[error("无效的路径")]
     InvalidPath,
@@ -45,6 +88,18 @@ pub enum Error {
     /// The filename is invalid.
     #[error("无效的文件名: {0}")]
     InvalidFilename(String),
+
+    /// The checksum of the transferred file does not match the local one.
+    #[error("文件校验失败: {0}, 本地 SHA-256: {1}, 远程 SHA-256: {2}")]
+    ChecksumMismatch(String, String, String),
+
+    /// The server host key differs from the pinned one.
+    #[error("服务器主机密钥已变更，可能存在中间人攻击，新的密钥指纹: {0}")]
+    HostKeyMismatch(String),
+
+    /// No rejected host key is waiting to be accepted.
+    #[error("未找到待确认的主机密钥: {0}")]
+    HostKeyNotFound(String),
 }
 
 impl Serialize for Error {
@@ -3,19 +3,21 @@ use std::path::PathBuf;
 use futures_util::StreamExt;
 use reqwest::Client;
 use tauri::Window;
-use tokio::fs::{create_dir_all, File};
+use tokio::fs::{create_dir_all, remove_file, File};
 use tokio::io::AsyncWriteExt;
 
+use crate::cancellation::{cancellable, CancellationToken};
 use crate::error::Error;
 use crate::progress_reporter::{EventProgressReporter, ProgressEvent, ProgressReporter};
 
 pub struct FileDownloader<'a> {
     progress_reporter: Box<dyn ProgressReporter + Send + Sync + 'a>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl<'a> FileDownloader<'a> {
     pub fn new(progress_reporter: Box<dyn ProgressReporter + Send + Sync + 'a>) -> Self {
-        Self { progress_reporter }
+        Self { progress_reporter, cancellation: None }
     }
 
     pub fn new_with_event_progress_reporter(window: &'a Window) -> Self {
@@ -23,11 +25,17 @@ impl<'a> FileDownloader<'a> {
         Self::new(progress_reporter)
     }
 
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Downloads the file at the url to the target file, a cancelled download removes the incomplete target file.
     pub async fn download_file(&self, url: &str, target_file: &str) -> Result<(), Error> {
         println!("Downloading file from {} to {}", url, target_file);
 
         let client = Client::new();
-        let response = client.get(url).send().await?;
+        let response = cancellable(self.cancellation.as_ref(), client.get(url).send()).await??;
         if !response.status().is_success() {
             return Err(Error::FlowExecutionFailed(format!("Failed to download file from {} to {}. Response status: {}, Response message: {}", url, target_file, response.status(), response.text().await?)));
         }
@@ -40,8 +48,16 @@ impl<'a> FileDownloader<'a> {
         let mut downloaded_size = 0;
         let mut stream = response.bytes_stream();
 
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
+        loop {
+            let chunk = match cancellable(self.cancellation.as_ref(), stream.next()).await {
+                Ok(Some(chunk)) => chunk?,
+                Ok(None) => break,
+                Err(e) => {
+                    drop(file);
+                    remove_file(target_file).await?;
+                    return Err(e);
+                }
+            };
             file.write_all(&chunk).await?;
             downloaded_size += chunk.len();
             self.progress_reporter.report_progress(ProgressEvent::new(total_size as usize, downloaded_size)).await;
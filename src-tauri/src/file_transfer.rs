@@ -1,20 +1,45 @@
-use std::cmp;
+use std::{cmp, io};
+use std::io::SeekFrom;
+use std::time::Instant;
 
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use russh_sftp::client::SftpSession;
+use russh_sftp::client::fs::File;
+use russh_sftp::protocol::OpenFlags;
+use serde::{Deserialize, Serialize};
 use tauri::Window;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
+use crate::cancellation::{cancellable, CancellationToken};
 use crate::error::Error;
 use crate::progress_reporter::{EventProgressReporter, ProgressEvent, ProgressReporter};
 
+/// The tuning of SFTP transfers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferOptions {
+    /// The size of the data sent by a single SFTP write request.
+    pub chunk_size: usize,
+    /// The number of SFTP write requests kept in flight, raising it helps high-latency links.
+    pub max_in_flight: usize,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self { chunk_size: 32 * 1024, max_in_flight: 16 }
+    }
+}
+
 pub struct FileTransfer<'a> {
     session: &'a mut SftpSession,
-    progress_reporter: Box<dyn ProgressReporter + Send + Sync + 'a>
+    progress_reporter: Box<dyn ProgressReporter + Send + Sync + 'a>,
+    options: TransferOptions,
+    cancellation: Option<CancellationToken>,
 }
 
 impl<'a> FileTransfer<'a> {
     pub fn new(session: &'a mut SftpSession, progress_reporter: Box<dyn ProgressReporter + Send + Sync + 'a>) -> Self {
-        Self { session, progress_reporter }
+        Self { session, progress_reporter, options: TransferOptions::default(), cancellation: None }
     }
 
     pub fn new_with_event_progress_reporter(session: &'a mut SftpSession, window: &'a Window) -> Self {
@@ -22,24 +47,156 @@ impl<'a> FileTransfer<'a> {
         Self::new(session, progress_reporter)
     }
 
-    pub async fn transfer_file(&mut self, file: &str, data: &[u8]) -> Result<(), Error> {
-        let mut file = self.session.create(file).await?;
+    pub fn with_options(mut self, options: TransferOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Transfers the data read from the source to the remote file starting at the offset,
+    /// the source must be positioned at the offset and provide exactly `total_size - offset` bytes.
+    /// The remote file is truncated if the offset is zero.
+    ///
+    /// Each write request in flight uses its own file handle, so the writes are pipelined instead of
+    /// waiting for the acknowledgement of the previous one.
+    /// A cancelled transfer stops without waiting for the writes in flight, the written part is kept.
+    pub async fn transfer_file<R: AsyncRead + Unpin + Send>(&mut self, file: &str, mut source: R, offset: u64, total_size: u64) -> Result<(), Error> {
+        let chunk_size = cmp::max(self.options.chunk_size, 1);
+        let max_in_flight = cmp::max(self.options.max_in_flight, 1);
+        let remaining = total_size.saturating_sub(offset);
+        let handles = cmp::max(cmp::min(max_in_flight as u64, remaining.div_ceil(chunk_size as u64)), 1) as usize;
 
-        let total_size = data.len();
-        let chunk_size = 8092;
-        let mut written = 0;
-        while written < total_size {
-            let end = cmp::min(written + chunk_size, total_size);
-            file.write_all(&data[written..end]).await?;
-            written = end;
+        let first = if offset == 0 {
+            self.session.create(file).await?
+        } else {
+            self.session.open_with_flags(file, OpenFlags::CREATE | OpenFlags::WRITE).await?
+        };
+        let mut idle_files = vec![first];
+        for _ in 1..handles {
+            idle_files.push(self.session.open_with_flags(file, OpenFlags::WRITE).await?);
+        }
+
+        let started_at = Instant::now();
+        let mut in_flight = FuturesUnordered::new();
+        let mut read_position = offset;
+        let mut written = offset as usize;
+        loop {
+            while read_position < total_size && !idle_files.is_empty() {
+                let len = cmp::min(chunk_size as u64, total_size - read_position);
+                let mut chunk = Vec::with_capacity(len as usize);
+                (&mut source).take(len).read_to_end(&mut chunk).await?;
+                if chunk.len() as u64 != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+
+                let mut file = idle_files.pop().unwrap();
+                let position = read_position;
+                read_position += len;
+                in_flight.push(async move {
+                    file.seek(SeekFrom::Start(position)).await?;
+                    file.write_all(&chunk).await?;
+                    Ok::<(File, usize), Error>((file, chunk.len()))
+                });
+            }
 
-            self.progress_reporter.report_progress(ProgressEvent::new(total_size, written)).await;
+            let Some(result) = cancellable(self.cancellation.as_ref(), in_flight.next()).await? else {
+                break;
+            };
+            let (file, len) = result?;
+            idle_files.push(file);
+            written += len;
+
+            let progress = ProgressEvent::new(total_size as usize, written)
+                .with_throughput(written - offset as usize, started_at.elapsed());
+            self.progress_reporter.report_progress(progress).await;
         }
 
-        file.sync_all().await?;
-        file.flush().await?;
+        // All handles refer to the same remote file, so it is synced once, `flush` would sync it again per handle.
+        for (index, mut file) in idle_files.into_iter().enumerate() {
+            if index == 0 {
+                file.sync_all().await?;
+            }
+            file.shutdown().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the remote file into the target.
+    pub async fn download_file<W: AsyncWrite + Unpin + Send>(&mut self, file: &str, mut target: W) -> Result<(), Error> {
+        let total_size = self.session.metadata(file).await?.len() as usize;
+        let mut file = self.session.open(file).await?;
+
+        let started_at = Instant::now();
+        let mut buffer = vec![0u8; cmp::max(self.options.chunk_size, 1)];
+        let mut downloaded = 0;
+        loop {
+            let read = cancellable(self.cancellation.as_ref(), file.read(&mut buffer)).await??;
+            if read == 0 {
+                break;
+            }
+
+            target.write_all(&buffer[..read]).await?;
+            downloaded += read;
+
+            let progress = ProgressEvent::new(total_size, downloaded)
+                .with_throughput(downloaded, started_at.elapsed());
+            self.progress_reporter.report_progress(progress).await;
+        }
+
+        target.flush().await?;
         file.shutdown().await?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytesize::ByteSize;
+
+    use super::*;
+    use crate::progress_reporter::NoopProgressReporter;
+    use crate::session::tests::{authenticate_test_session, connect_test_server, test_sftp};
+
+    const BENCHMARK_FILE: &str = "/tmp/vef-transfer-benchmark";
+
+    /// Measures the upload throughput to an OpenSSH server for chunk sizes and numbers of writes in flight,
+    /// which `TransferOptions::default` is chosen from. The latency of a remote link can be simulated on a
+    /// local server with `tc qdisc add dev lo root netem delay 20ms`:
+    /// `VEF_TEST_SSH_USER=... VEF_TEST_SSH_PASSWORD=... cargo test benchmark_upload -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn benchmark_upload() {
+        let mut session = connect_test_server().await;
+        authenticate_test_session(&mut session).await;
+        let sftp = test_sftp(&mut session).await;
+        let data = vec![0x5a; 64 * 1024 * 1024];
+
+        for chunk_size in [8 * 1024, 32 * 1024, 64 * 1024, 128 * 1024] {
+            for max_in_flight in [1, 4, 16, 64] {
+                let started_at = Instant::now();
+                FileTransfer::new(&mut *sftp, Box::new(NoopProgressReporter))
+                    .with_options(TransferOptions { chunk_size, max_in_flight })
+                    .transfer_file(BENCHMARK_FILE, Cursor::new(&data), 0, data.len() as u64).await
+                    .expect("Failed to upload the benchmark file");
+
+                let throughput = (data.len() as f64 / started_at.elapsed().as_secs_f64()) as u64;
+                println!(
+                    "chunk size {:>9}, in flight {:>2}: {}/s",
+                    ByteSize::b(chunk_size as u64).to_string_as(false),
+                    max_in_flight,
+                    ByteSize::b(throughput).to_string_as(false),
+                );
+            }
+        }
+
+        sftp.remove_file(BENCHMARK_FILE).await.expect("Failed to remove the benchmark file");
+    }
+}
@@ -1,22 +1,34 @@
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::io;
-use std::path::PathBuf;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bytesize::ByteSize;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use similar::TextDiff;
 use tauri::{Manager, Window};
 use tera::Context;
 use tokio::fs;
 use tokio::fs::try_exists;
+use tokio::time::{sleep, timeout};
 
+use crate::cancellation::{cancellable, CancellationToken};
 use crate::decompressor::{Decompressor, ProgressDecompressor};
 use crate::error::Error;
 use crate::flow_asset::FlowAsset;
+use crate::flow_condition::StepCondition;
+use crate::flow_report::{FlowReporter, FlowRunSummary, RegisteredVariable, RunCheckpoint, StepOutput};
 use crate::hospital_settings::HospitalSettings;
+use crate::pending_requests::Confirmations;
 use crate::package_resolver::{PackageResolver, RemoteServerPackageResolver};
-use crate::session::Session;
+use crate::session::{FilePermissions, Session};
 use crate::template::TemplateEvaluator;
+use crate::util::sha256_digest;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -27,6 +39,40 @@ pub struct FlowDefinition {
     icon: String,
     parameters: Vec<FlowParameter>,
     steps: Vec<FlowStepDefinition>,
+    /// The steps run after a step failed and the completed steps were rolled back.
+    #[serde(default)]
+    on_failure: Vec<FlowStepDefinition>,
+    /// The names of the flows which must have run before this flow, each of them requires `readyIf`.
+    #[serde(default)]
+    depends_on: Vec<String>,
+    /// The check whether the flow has already been applied, so it is not run again as a prerequisite.
+    ready_if: Option<StepCondition>,
+}
+
+impl FlowDefinition {
+    /// Get the names of the flows this flow runs: its prerequisites and the flows of its `runFlow` steps.
+    fn called_flows(&self) -> Vec<&str> {
+        let mut names = self.prerequisites();
+        names.extend(self.all_steps().into_iter()
+            .filter(|step| step.r#type == "runFlow")
+            .filter_map(|step| step.flow.as_deref()));
+        names
+    }
+
+    /// Get the steps, the `onFailure` steps and their rollback steps.
+    fn all_steps(&self) -> Vec<&FlowStepDefinition> {
+        let mut all = vec![];
+        let mut steps: Vec<&FlowStepDefinition> = self.steps.iter().chain(&self.on_failure).collect();
+        while let Some(step) = steps.pop() {
+            all.push(step);
+            steps.extend(&step.rollback);
+        }
+        all
+    }
+
+    fn prerequisites(&self) -> Vec<&str> {
+        self.depends_on.iter().map(String::as_str).collect()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -44,13 +90,64 @@ pub struct FlowParameter {
 pub struct FlowStepDefinition {
     r#type: String,
     name: String,
+    /// The shell command skipping the step if it succeeds, prefer `skipIf` and `runIf`.
     condition: Option<String>,
+    /// The step is skipped if the condition holds.
+    skip_if: Option<StepCondition>,
+    /// The step is skipped unless the condition holds.
+    run_if: Option<StepCondition>,
     command: Option<String>,
     package: Option<String>,
     source_file_param_name: Option<String>,
     source_file: Option<String>,
+    source_dir: Option<String>,
     target_dir: Option<String>,
     target_file: Option<String>,
+    preserve_mtime: Option<bool>,
+    /// The octal permission bits of the transferred files, e.g. `0600`.
+    mode: Option<String>,
+    owner: Option<String>,
+    group: Option<String>,
+    /// The time limit of a single attempt of the step.
+    timeout_seconds: Option<u64>,
+    /// How many times a failed step is retried.
+    retries: Option<u32>,
+    /// The delay before the first retry, doubled for every further retry.
+    retry_delay_seconds: Option<u64>,
+    /// The steps undoing this step, run if a later step fails.
+    #[serde(default)]
+    rollback: Vec<FlowStepDefinition>,
+    /// The flow run by a `runFlow` step, a resumed run reruns it from its first step.
+    flow: Option<String>,
+    /// The args of the flow run by a `runFlow` step, added to the args of this flow. String values are templates.
+    args: Option<HashMap<String, Value>>,
+    /// The name of the variable the stdout of a `runCommand` step is registered as, used as `vars.<name>`.
+    register: Option<String>,
+    /// How the stdout is turned into the registered variable, trimmed by default.
+    register_format: Option<RegisterFormat>,
+}
+
+/// How the stdout of a command is turned into a registered variable.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RegisterFormat {
+    Raw,
+    /// The stdout without leading and trailing whitespace.
+    #[default]
+    Trimmed,
+    /// The stdout parsed as JSON.
+    Json,
+}
+
+impl RegisterFormat {
+    fn parse(&self, stdout: &str) -> Result<Value, Error> {
+        match self {
+            RegisterFormat::Raw => Ok(Value::String(stdout.to_owned())),
+            RegisterFormat::Trimmed => Ok(Value::String(stdout.trim().to_owned())),
+            RegisterFormat::Json => serde_json::from_str(stdout)
+                .map_err(|e| Error::FlowExecutionFailed(format!("Invalid JSON output: {}", e))),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -87,11 +184,107 @@ pub struct FlowEngine {
     template_evaluator: TemplateEvaluator,
 }
 
+/// The options of a flow run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowRunOptions {
+    /// Whether to preview the changes of config files and wait for the operator to confirm them before pushing.
+    #[serde(default)]
+    pub confirm_config_changes: bool,
+    /// The index of the step to start at, the steps before are skipped as already completed.
+    pub start_index: Option<usize>,
+    /// The variables registered by the skipped steps in a previous run.
+    #[serde(default)]
+    pub variables: HashMap<String, Value>,
+    /// The names of the prerequisite flows which have run or were ready, shared by the flows within this run
+    /// so a prerequisite is handled only once.
+    #[serde(skip)]
+    satisfied_prerequisites: Arc<Mutex<HashSet<String>>>,
+}
+
+impl FlowRunOptions {
+    /// Get the options of a flow run within this run, which starts at its first step.
+    fn nested(&self) -> FlowRunOptions {
+        FlowRunOptions {
+            confirm_config_changes: self.confirm_config_changes,
+            satisfied_prerequisites: self.satisfied_prerequisites.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The plan of a flow run, describing what each step would do without doing it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowPlan {
+    flow_name: String,
+    /// The prerequisites of the flow and of the flows it runs, in the order they are checked.
+    prerequisites: Vec<PrerequisitePlan>,
+    /// The steps of the flow, of its prerequisites which would run and of the flows of its `runFlow` steps,
+    /// in the order they would run.
+    steps: Vec<FlowStepPlan>,
+}
+
+/// The plan of a prerequisite flow, whose steps are only planned if its readiness check does not hold.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrerequisitePlan {
+    flow_name: String,
+    /// The flow depending on the prerequisite.
+    required_by: String,
+    /// Whether the readiness check holds, so the prerequisite would not run.
+    ready: bool,
+    /// The readiness check.
+    condition: Option<String>,
+    /// The error which prevented evaluating the readiness check.
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowStepPlan {
+    /// The flow the step belongs to.
+    flow_name: String,
+    index: usize,
+    name: String,
+    r#type: String,
+    /// Whether the step would run, `false` if one of its conditions skips it.
+    will_run: bool,
+    /// The condition which would skip the step.
+    condition: Option<String>,
+    command: Option<String>,
+    package: Option<String>,
+    target_dir: Option<String>,
+    target_file: Option<String>,
+    /// The unified diff of a config file against the current remote file.
+    config_diff: Option<String>,
+    /// The variables registered by earlier steps which the step uses, they are only known at runtime
+    /// and rendered as `{{ vars.<name> }}`.
+    runtime_variables: Vec<String>,
+    /// The error which prevented planning the step completely.
+    error: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
-struct FlowStepChangeEvent {
-    data: usize,
+#[serde(rename_all = "camelCase")]
+struct ConfigDiffPreviewEvent<'a> {
+    request_id: u64,
+    target_file: &'a str,
+    diff: &'a str,
 }
 
+/// How long to wait for the operator to confirm the changes of a config file.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+
+/// The delay before the first retry of a step which does not set `retryDelaySeconds`.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The upper bound of the growing delay between retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 5);
+
+/// The time a step which timed out is given to terminate its command after it is cancelled.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(10);
+
 impl FlowEngine {
     pub fn new() -> Self {
         let container = FlowContainer::new();
@@ -110,32 +303,434 @@ impl FlowEngine {
         self.container.get_all_flows()
     }
 
-    pub async fn run_flow(&self, flow_name: &str, hospital_settings: HospitalSettings, args: HashMap<String, Value>, session: &mut Session, window: &Window) -> Result<(), Error> {
-        if let Some(flow) = self.container.get_flow(flow_name) {
-            let context = self.create_template_evaluation_context(&hospital_settings, &args, session).await?;
-            for (index, step) in flow.steps.iter().enumerate() {
-                window.emit("flow-step-change", FlowStepChangeEvent {
-                    data: index
+    /// Run the flow, reporting the result of each step.
+    /// Returns the run summary together with the result, the summary is available even if the run failed.
+    pub async fn run_flow(&self, flow_name: &str, hospital_settings: HospitalSettings, args: HashMap<String, Value>, options: &FlowRunOptions, checkpoint: Option<&dyn RunCheckpoint>, session: &mut Session, window: &Window) -> (FlowRunSummary, Result<(), Error>) {
+        let mut reporter = FlowReporter::new(flow_name, window).with_checkpoint(checkpoint);
+        let result = match self.container.get_flow(flow_name) {
+            Some(flow) => match self.check_flow_graph(flow) {
+                Ok(_) => self.run_with_prerequisites(flow, &hospital_settings, &args, options, &mut reporter, session, window).await,
+                Err(e) => Err(e),
+            },
+            None => Err(Error::FlowExecutionFailed(format!("Flow not found: {}", flow_name))),
+        };
+        let summary = reporter.finish(result.as_ref().err());
+
+        (summary, result)
+    }
+
+    /// Check that the flows called by the flow, directly or transitively, exist and do not call each other in a cycle,
+    /// and that the prerequisites have a readiness check, without it they would run every time.
+    /// Only `runCommand` steps may register variables, which is checked before any step runs.
+    fn check_flow_graph(&self, flow: &FlowDefinition) -> Result<(), Error> {
+        let mut flows = vec![];
+        self.visit_flows(flow, FlowDefinition::called_flows, &mut vec![], &mut flows)?;
+        for flow in flows {
+            if let Some(step) = flow.all_steps().into_iter().find(|step| step.register.is_some() && step.r#type != "runCommand") {
+                return Err(Error::FlowExecutionFailed(format!("Only runCommand steps can register a variable, step {} of flow {}", step.name, flow.name)));
+            }
+            for name in flow.prerequisites() {
+                if self.container.get_flow(name).is_some_and(|prerequisite| prerequisite.ready_if.is_none()) {
+                    return Err(Error::FlowExecutionFailed(format!("Prerequisite flow {} of flow {} has no readyIf", name, flow.name)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the prerequisites of the flow, transitively, in the order they must run.
+    fn resolve_prerequisites<'a>(&'a self, flow: &'a FlowDefinition) -> Result<Vec<&'a FlowDefinition>, Error> {
+        let mut ordered = vec![];
+        self.visit_flows(flow, FlowDefinition::prerequisites, &mut vec![], &mut ordered)?;
+        // The flow itself is visited last.
+        ordered.pop();
+        Ok(ordered)
+    }
+
+    /// Visit the flows reachable from the flow over the edges depth first, each flow is appended to `ordered`
+    /// after the flows it reaches.
+    fn visit_flows<'a>(&'a self, flow: &'a FlowDefinition, edges: fn(&FlowDefinition) -> Vec<&str>, path: &mut Vec<&'a str>, ordered: &mut Vec<&'a FlowDefinition>) -> Result<(), Error> {
+        if ordered.iter().any(|visited| visited.name == flow.name) {
+            return Ok(());
+        }
+        if path.contains(&flow.name.as_str()) {
+            path.push(&flow.name);
+            return Err(Error::FlowExecutionFailed(format!("Circular flow dependency: {}", path.join(" -> "))));
+        }
+
+        path.push(&flow.name);
+        for name in edges(flow) {
+            let next = self.container.get_flow(name)
+                .ok_or(Error::FlowExecutionFailed(format!("Flow not found: {}", name)))?;
+            self.visit_flows(next, edges, path, ordered)?;
+        }
+        path.pop();
+        ordered.push(flow);
+
+        Ok(())
+    }
+
+    /// Run the prerequisites of the flow whose readiness checks do not hold, then the flow itself.
+    /// The prerequisites are not run again when a run is resumed, nor when they were already handled
+    /// for another flow within the run.
+    async fn run_with_prerequisites(&self, flow: &FlowDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, reporter: &mut FlowReporter<'_>, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let prerequisites = if options.start_index.unwrap_or(0) == 0 { self.resolve_prerequisites(flow)? } else { vec![] };
+        if !prerequisites.is_empty() {
+            let context = self.create_template_evaluation_context(hospital_settings, args, &HashMap::new(), session).await?;
+            let nested_options = options.nested();
+            for prerequisite in prerequisites {
+                if options.satisfied_prerequisites.lock().unwrap().contains(&prerequisite.name) {
+                    continue;
+                }
+                if let Some(ref ready_if) = prerequisite.ready_if {
+                    let (condition, ready) = ready_if.evaluate(&self.template_evaluator, &context, session).await?;
+                    if ready {
+                        println!("Prerequisite flow {} is ready: {}", prerequisite.name, condition);
+                        reporter.prerequisite_ready(&prerequisite.name, &condition);
+                        options.satisfied_prerequisites.lock().unwrap().insert(prerequisite.name.clone());
+                        continue;
+                    }
+                }
+
+                println!("Running prerequisite flow: {}", prerequisite.name);
+                let mut nested_reporter = FlowReporter::nested(&prerequisite.name, window);
+                let result = self.run_flow_with_rollback(prerequisite, hospital_settings, args, &nested_options, &mut nested_reporter, session, window).await;
+                reporter.prerequisite_finished(&nested_reporter.finish(result.as_ref().err()));
+                result.map_err(|e| match e {
+                    Error::Cancelled => Error::Cancelled,
+                    e => Error::FlowExecutionFailed(format!("Prerequisite flow {} failed: {}", prerequisite.name, e)),
                 })?;
+                options.satisfied_prerequisites.lock().unwrap().insert(prerequisite.name.clone());
+            }
+        }
+
+        self.run_flow_with_rollback(flow, hospital_settings, args, options, reporter, session, window).await
+    }
+
+    /// Run the flow of a `runFlow` step with its prerequisites, returning its summary and result.
+    /// The future is boxed since the flow runs within the steps of another flow.
+    /// The steps of the nested flow are not saved to the run checkpoint, only the `runFlow` step is, so a resumed
+    /// run starts the nested flow over from its first step. Its completed steps were rolled back when it failed,
+    /// and its prerequisites are checked again.
+    fn run_nested_flow<'a>(&'a self, flow: &'a FlowDefinition, hospital_settings: &'a HospitalSettings, args: &'a HashMap<String, Value>, options: &'a FlowRunOptions, session: &'a mut Session, window: &'a Window) -> Pin<Box<dyn Future<Output=(FlowRunSummary, Result<(), Error>)> + Send + 'a>> {
+        Box::pin(async move {
+            let mut reporter = FlowReporter::nested(&flow.name, window);
+            let result = self.run_with_prerequisites(flow, hospital_settings, args, &options.nested(), &mut reporter, session, window).await;
+            (reporter.finish(result.as_ref().err()), result)
+        })
+    }
+
+    /// Run the steps of the flow. If a step fails, the rollback steps of the completed steps are run
+    /// in reverse order of the steps, followed by the `onFailure` steps of the flow.
+    async fn run_flow_with_rollback(&self, flow: &FlowDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, reporter: &mut FlowReporter<'_>, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let mut context = self.create_template_evaluation_context(hospital_settings, args, &options.variables, session).await?;
+        let mut completed = vec![];
+        let result = self.run_steps(flow, hospital_settings, args, options, &mut context, &mut completed, reporter, session, window).await;
+        if result.is_err() {
+            let rollback_steps: Vec<_> = completed.iter().rev()
+                .flat_map(|&index| flow.steps[index].rollback.iter().map(move |step| (Some(index), step)))
+                .chain(flow.on_failure.iter().map(|step| (None, step)))
+                .collect();
+
+            // The rollback undoes a cancelled run as well, so it must not be cancelled itself,
+            // only the timeouts of the rollback steps stop them.
+            let cancellation = session.cancellation();
+            session.set_cancellation(None);
+            for (step_index, step) in rollback_steps {
+                self.run_rollback_step(step_index, step, hospital_settings, args, options, &context, reporter, session, window).await;
+            }
+            session.set_cancellation(cancellation);
+        }
+
+        result
+    }
+
+    /// Run a rollback or `onFailure` step, its failure is reported but does not stop the rollback.
+    async fn run_rollback_step(&self, step_index: Option<usize>, step: &FlowStepDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, context: &Context, reporter: &mut FlowReporter<'_>, session: &mut Session, window: &Window) {
+        println!("Rolling back with step: {}", step.name);
+        let started = Instant::now();
+        let mut output = StepOutput::default();
+        let result = match self.check_condition(step, context, session).await {
+            Ok(Some(condition)) => {
+                reporter.rollback_step_skipped(step_index, &step.name, condition, started);
+                return;
+            }
+            Ok(None) => {
+                let retrying = |attempt, e: &Error| {
+                    reporter.rollback_step_retrying(&step.name, attempt, e);
+                    Ok(())
+                };
+                self.handle_step_with_retries(step, hospital_settings, args, options, context, &mut output, retrying, session, window).await
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(ref e) = result {
+            println!("Rollback step {} failed: {:?}", step.name, e);
+        }
+        reporter.rollback_step_finished(step_index, &step.name, output, started, &result);
+    }
+
+    /// Run the steps of the flow, collecting the indexes of the steps which completed.
+    async fn run_steps(&self, flow: &FlowDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, context: &mut Context, completed: &mut Vec<usize>, reporter: &mut FlowReporter<'_>, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let cancellation = session.cancellation();
+        for (index, step) in flow.steps.iter().enumerate() {
+            reporter.step_changed(index)?;
+
+            let started = Instant::now();
+            if options.start_index.is_some_and(|start_index| index < start_index) {
+                let registered = step.register.as_ref()
+                    .and_then(|name| options.variables.get(name).map(|value| RegisteredVariable { name: name.clone(), value: value.clone() }));
+                reporter.step_skipped(index, &step.name, None, registered, started)?;
+                continue;
+            }
+
+            reporter.step_started(index, &step.name)?;
+            let mut output = StepOutput::default();
+            let condition = match cancellation.as_ref().map_or(Ok(()), CancellationToken::check) {
+                Ok(_) => self.check_condition(step, context, session).await,
+                Err(e) => Err(e),
+            };
+            let result = match condition {
+                Ok(Some(condition)) => {
+                    println!("Skipping step {} by condition: {}", step.name, condition);
+                    reporter.step_skipped(index, &step.name, Some(condition), None, started)?;
+                    continue;
+                }
+                Ok(None) => {
+                    let retrying = |attempt, e: &Error| reporter.step_retrying(index, &step.name, attempt, e);
+                    self.handle_step_with_retries(step, hospital_settings, args, options, context, &mut output, retrying, session, window).await
+                }
+                Err(e) => Err(e),
+            };
+
+            let registered = output.registered.clone();
+            reporter.step_finished(index, &step.name, output, started, &result)?;
+            result?;
+            completed.push(index);
+            if let Some(variable) = registered {
+                register_variable(context, variable);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle the step within its timeout, retrying failed attempts with a doubling delay.
+    /// Cancelled runs and rejected config changes are not retried.
+    async fn handle_step_with_retries<F>(&self, step: &FlowStepDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, context: &Context, output: &mut StepOutput, mut retrying: F, session: &mut Session, window: &Window) -> Result<(), Error>
+    where
+        F: FnMut(u32, &Error) -> Result<(), Error>,
+    {
+        let retries = step.retries.unwrap_or(0);
+        let mut delay = step.retry_delay_seconds.map(Duration::from_secs).unwrap_or(DEFAULT_RETRY_DELAY);
+        let mut attempt = 0;
+        loop {
+            *output = StepOutput::default();
+            let result = match step.timeout_seconds {
+                Some(seconds) => self.handle_step_with_timeout(step, hospital_settings, args, options, context, output, Duration::from_secs(seconds), session, window).await,
+                None => self.handle_step(step, hospital_settings, args, options, context, output, session, window).await,
+            };
+
+            match result {
+                Err(Error::Cancelled | Error::ConfigChangeRejected(_)) | Ok(_) => return result,
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    println!("Step {} failed, retrying in {:?} ({}/{}): {:?}", step.name, delay, attempt, retries, e);
+                    retrying(attempt, &e)?;
+                    cancellable(session.cancellation().as_ref(), sleep(delay)).await?;
+                    delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Run a single attempt of the step within the time limit. When the limit is exceeded the attempt is cancelled,
+    /// which terminates its remote command, and given `TIMEOUT_GRACE` to stop before it fails with a timeout.
+    async fn handle_step_with_timeout(&self, step: &FlowStepDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, context: &Context, output: &mut StepOutput, limit: Duration, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let cancellation = session.cancellation();
+        let attempt = cancellation.as_ref().map(CancellationToken::child).unwrap_or_default();
+        session.set_cancellation(Some(attempt.clone()));
+
+        let result = {
+            let mut handle = pin!(self.handle_step(step, hospital_settings, args, options, context, output, session, window));
+            tokio::select! {
+                result = &mut handle => result,
+                _ = sleep(limit) => {
+                    println!("Step {} timed out after {:?}, cancelling it", step.name, limit);
+                    attempt.cancel();
+                    if timeout(TIMEOUT_GRACE, handle).await.is_err() {
+                        println!("Step {} did not stop within {:?}", step.name, TIMEOUT_GRACE);
+                    }
+                    if cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                        Err(Error::Cancelled)
+                    } else {
+                        Err(Error::CommandExecutionTimeout)
+                    }
+                }
+            }
+        };
+        session.set_cancellation(cancellation);
+
+        result
+    }
+
+    /// Check the conditions of the step, returning the description of the condition which skips the step.
+    async fn check_condition(&self, step: &FlowStepDefinition, context: &Context, session: &mut Session) -> Result<Option<String>, Error> {
+        if let Some(ref script) = step.condition {
+            let condition = self.render_template(script, context)?;
+            if session.test_command(&condition).await? {
+                return Ok(Some(condition));
+            }
+        }
+
+        if let Some(ref condition) = step.skip_if {
+            let (description, holds) = condition.evaluate(&self.template_evaluator, context, session).await?;
+            if holds {
+                return Ok(Some(format!("skipIf {}", description)));
+            }
+        }
+
+        if let Some(ref condition) = step.run_if {
+            let (description, holds) = condition.evaluate(&self.template_evaluator, context, session).await?;
+            if !holds {
+                return Ok(Some(format!("runIf {}", description)));
+            }
+        }
 
-                if let Some(ref script) = step.condition {
-                    let result = session.test_command(
-                        &self.render_template(script, &context)?,
-                    ).await?;
-                    if result {
-                        println!("Condition is true, skipping step: {}", step.name);
+        Ok(None)
+    }
+
+    /// Plan the flow: evaluate the conditions and render the templates of every step
+    /// without running mutating commands or transfers.
+    /// The prerequisites whose readiness checks do not hold and the flows of `runFlow` steps are planned as well,
+    /// their steps are tagged with the name of their flow.
+    pub async fn plan_flow(&self, flow_name: &str, hospital_settings: HospitalSettings, args: HashMap<String, Value>, session: &mut Session, window: &Window) -> Result<FlowPlan, Error> {
+        let flow = self.container.get_flow(flow_name)
+            .ok_or(Error::FlowExecutionFailed(format!("Flow not found: {}", flow_name)))?;
+        self.check_flow_graph(flow)?;
+
+        let mut plan = FlowPlan { flow_name: flow.name.clone(), prerequisites: vec![], steps: vec![] };
+        self.plan_with_prerequisites(flow, &hospital_settings, &args, &mut HashSet::new(), &mut plan, session, window).await?;
+        Ok(plan)
+    }
+
+    /// Plan the prerequisites of the flow, then the flow itself, like `run_with_prerequisites` runs them.
+    /// A prerequisite is planned once per plan, as it is only run once per run.
+    /// The future is boxed since the flow of a `runFlow` step is planned within the steps of another flow.
+    fn plan_with_prerequisites<'a>(&'a self, flow: &'a FlowDefinition, hospital_settings: &'a HospitalSettings, args: &'a HashMap<String, Value>, satisfied: &'a mut HashSet<String>, plan: &'a mut FlowPlan, session: &'a mut Session, window: &'a Window) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send + 'a>> {
+        Box::pin(async move {
+            let prerequisites = self.resolve_prerequisites(flow)?;
+            if !prerequisites.is_empty() {
+                let context = self.create_template_evaluation_context(hospital_settings, args, &HashMap::new(), session).await?;
+                for prerequisite in prerequisites {
+                    if !satisfied.insert(prerequisite.name.clone()) {
                         continue;
                     }
+
+                    let mut prerequisite_plan = PrerequisitePlan { flow_name: prerequisite.name.clone(), required_by: flow.name.clone(), ..Default::default() };
+                    if let Some(ref ready_if) = prerequisite.ready_if {
+                        match ready_if.evaluate(&self.template_evaluator, &context, session).await {
+                            Ok((condition, ready)) => {
+                                prerequisite_plan.ready = ready;
+                                prerequisite_plan.condition = Some(condition);
+                            }
+                            Err(e) => prerequisite_plan.error = Some(e.to_string()),
+                        }
+                    }
+                    let ready = prerequisite_plan.ready;
+                    plan.prerequisites.push(prerequisite_plan);
+                    if !ready {
+                        self.plan_steps(prerequisite, hospital_settings, args, satisfied, plan, session, window).await?;
+                    }
+                }
+            }
+
+            self.plan_steps(flow, hospital_settings, args, satisfied, plan, session, window).await
+        })
+    }
+
+    /// Plan the steps of the flow, followed by the flow of each `runFlow` step which would run.
+    async fn plan_steps(&self, flow: &FlowDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, satisfied: &mut HashSet<String>, plan: &mut FlowPlan, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let mut context = self.create_template_evaluation_context(hospital_settings, args, &HashMap::new(), session).await?;
+
+        let mut registered: Vec<String> = vec![];
+        for (index, step) in flow.steps.iter().enumerate() {
+            let definition = serde_json::to_string(step).map_err(std::io::Error::from)?;
+            let mut step_plan = FlowStepPlan {
+                flow_name: flow.name.clone(),
+                index,
+                name: step.name.clone(),
+                r#type: step.r#type.clone(),
+                will_run: true,
+                runtime_variables: registered.iter()
+                    .filter(|name| uses_variable(&definition, name))
+                    .cloned()
+                    .collect(),
+                ..Default::default()
+            };
+            match self.plan_step(&mut step_plan, step, &context, session, window).await {
+                // A template using a variable only known at runtime may fail with its placeholder, e.g. a JSON field.
+                Err(Error::TemplateError(_)) if !step_plan.runtime_variables.is_empty() => {}
+                Err(e) => step_plan.error = Some(e.to_string()),
+                Ok(_) => {}
+            }
+
+            let nested = match step.flow {
+                Some(ref flow_name) if step.r#type == "runFlow" && step_plan.will_run && step_plan.error.is_none() => {
+                    match self.create_nested_flow_args(step, args, &context) {
+                        Ok(nested_args) => self.container.get_flow(flow_name).map(|nested| (nested, nested_args)),
+                        Err(e) => {
+                            step_plan.error = Some(e.to_string());
+                            None
+                        }
+                    }
                 }
+                _ => None,
+            };
+            plan.steps.push(step_plan);
+            if let Some((nested, nested_args)) = nested {
+                self.plan_with_prerequisites(nested, hospital_settings, &nested_args, satisfied, plan, session, window).await?;
+            }
+
+            // The registered variables are rendered as placeholders, their values are only known at runtime.
+            if let Some(ref name) = step.register {
+                register_variable(&mut context, RegisteredVariable { name: name.clone(), value: Value::String(format!("{{{{ vars.{} }}}}", name)) });
+                registered.push(name.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn plan_step(&self, plan: &mut FlowStepPlan, step: &FlowStepDefinition, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+        plan.condition = self.check_condition(step, context, session).await?;
+        plan.will_run = plan.condition.is_none();
 
-                self.handle_step(step, &args, &context, session, window).await?;
+        let render = |template: &Option<String>| template.as_ref()
+            .map(|template| self.render_template(template, context))
+            .transpose();
+        plan.command = match step.r#type.as_str() {
+            "runFlow" => step.flow.as_ref().map(|flow| format!("runFlow {}", flow)),
+            _ => render(&step.command)?,
+        };
+        plan.package = render(&step.package)?;
+        plan.target_dir = render(&step.target_dir)?;
+        plan.target_file = render(&step.target_file)?;
+
+        if step.r#type == "transferConfigFile" {
+            if let (Some(source_file), Some(target_file)) = (&step.source_file, &step.target_file) {
+                let (target_file, config_content) = self.render_config_file(source_file, target_file, context, window).await?;
+                plan.config_diff = Some(self.diff_config_file(&target_file, &config_content, session).await?);
             }
         }
 
         Ok(())
     }
 
-    async fn handle_step(&self, step: &FlowStepDefinition, args: &HashMap<String, Value>, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+    async fn handle_step(&self, step: &FlowStepDefinition, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, options: &FlowRunOptions, context: &Context, output: &mut StepOutput, session: &mut Session, window: &Window) -> Result<(), Error> {
         match step.r#type.as_str() {
             "decompressionOfflinePackage" => {
                 let offline_package = args.get("offline_package").expect("No offline package specified")
@@ -145,26 +740,57 @@ impl FlowEngine {
             }
             "runCommand" => {
                 if let Some(ref command) = step.command {
-                    self.handle_command_step(command, context, session, window).await
+                    self.handle_command_step(command, step.register.is_some(), context, output, session, window).await?;
+                    if let Some(ref name) = step.register {
+                        let stdout = output.stdout.as_deref().unwrap_or_default();
+                        let value = step.register_format.unwrap_or_default().parse(stdout)?;
+                        output.registered = Some(RegisteredVariable { name: name.clone(), value });
+                    }
+                    Ok(())
                 } else {
                     Err(Error::FlowExecutionFailed(format!("No command specified for step {}", step.name)))
                 }
             }
+            "runFlow" => {
+                let flow_name = step.flow.as_ref().ok_or(Error::FlowExecutionFailed(format!("No flow specified for step {}", step.name)))?;
+                let flow = self.container.get_flow(flow_name)
+                    .ok_or(Error::FlowExecutionFailed(format!("Flow not found: {}", flow_name)))?;
+                let args = self.create_nested_flow_args(step, args, context)?;
+                output.command = Some(format!("runFlow {}", flow_name));
+                let (summary, result) = self.run_nested_flow(flow, hospital_settings, &args, options, session, window).await;
+                output.stdout = Some(summary.log);
+                result
+            }
             "downloadPackage" => {
-                self.download_package(step, context, window).await
+                self.download_package(step, context, session.cancellation(), window).await
             }
             "transferPackage" => {
                 let package = step.package.as_ref().expect(format!("No package specified for step {}", step.name).as_str());
                 let target_file = step.target_file.as_ref().expect(format!("No target file specified for step {}", step.name).as_str());
-                self.transfer_package(package, target_file, context, session, window).await
+                let permissions = self.create_file_permissions(step, context)?;
+                self.transfer_package(package, target_file, &permissions, context, session, window).await
             }
             "transferConfigFile" => {
                 let source_file = step.source_file.as_ref().expect(format!("No source file specified for step {}", step.name).as_str());
                 let target_file = step.target_file.as_ref().expect(format!("No target file specified for step {}", step.name).as_str());
-                self.transfer_config_file(source_file, target_file, context, session, window).await
+                let permissions = self.create_file_permissions(step, context)?;
+                self.transfer_config_file(source_file, target_file, &permissions, options, context, session, window).await
             }
             "transferFile" => {
-                self.transfer_file(step, args, session, window).await
+                let permissions = self.create_file_permissions(step, context)?;
+                self.transfer_file(step, args, &permissions, session, window).await
+            }
+            "transferDirectory" => {
+                let permissions = self.create_file_permissions(step, context)?;
+                self.transfer_directory(step, args, &permissions, context, session, window).await
+            }
+            "fetchFile" => {
+                let source_file = step.source_file.as_ref()
+                    .ok_or(Error::FlowExecutionFailed(format!("No source file specified for step {}", step.name)))?;
+                let source_file = self.render_template(source_file, context)?;
+                let target_file = step.target_file.as_ref().map(|target_file| self.render_template(target_file, context)).transpose()?;
+                self.fetch_file(&hospital_settings.id, &source_file, target_file.as_deref(), session, window).await?;
+                Ok(())
             }
             _ => {
                 Err(Error::FlowExecutionFailed(format!("Unsupported step type: {}", step.r#type)))
@@ -182,11 +808,29 @@ impl FlowEngine {
             .await
     }
 
-    /// Execute the command on the target machine.
-    async fn handle_command_step(&self, command: &str, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+    /// Execute the command on the target machine, capturing its whole stdout if `capture_stdout` is set.
+    async fn handle_command_step(&self, command: &str, capture_stdout: bool, context: &Context, output: &mut StepOutput, session: &mut Session, window: &Window) -> Result<(), Error> {
         let command = self.template_evaluator.render(command, context)?;
-        session.execute_command_stream(&command, window).await?;
-        Ok(())
+        output.command = Some(command.clone());
+        let command_output = session.run_command_stream(&command, capture_stdout, window).await?;
+        output.exit_code = command_output.exit_code;
+        output.stdout = Some(command_output.stdout.clone());
+        output.stderr = Some(command_output.stderr.clone());
+        command_output.check()
+    }
+
+    /// Create the args of the flow of a `runFlow` step: the args of this flow with the rendered args of the step.
+    fn create_nested_flow_args(&self, step: &FlowStepDefinition, args: &HashMap<String, Value>, context: &Context) -> Result<HashMap<String, Value>, Error> {
+        let mut nested_args = args.clone();
+        for (name, value) in step.args.iter().flatten() {
+            let value = match value {
+                Value::String(template) => Value::String(self.render_template(template, context)?),
+                value => value.clone(),
+            };
+            nested_args.insert(name.clone(), value);
+        }
+
+        Ok(nested_args)
     }
 
     /// Get the OS version of the target machine.
@@ -196,18 +840,19 @@ impl FlowEngine {
     }
 
     /// Create template evaluation context.
-    async fn create_template_evaluation_context(&self, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, session: &mut Session) -> Result<Context, Error> {
+    async fn create_template_evaluation_context(&self, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>, variables: &HashMap<String, Value>, session: &mut Session) -> Result<Context, Error> {
         let mut context = Context::new();
         context.insert("os", &self.get_os_version(session).await?);
         context.insert("hs", hospital_settings);
         context.insert("args", args);
+        context.insert("vars", variables);
         println!("Template evaluation context: {:?}", context);
 
         Ok(context)
     }
 
     /// Download package from the server.
-    async fn download_package(&self, step: &FlowStepDefinition, context: &Context, window: &Window) -> Result<(), Error> {
+    async fn download_package(&self, step: &FlowStepDefinition, context: &Context, cancellation: Option<CancellationToken>, window: &Window) -> Result<(), Error> {
         let mut cache_dir = window.app_handle().path_resolver().app_cache_dir()
             .ok_or(io::Error::new(io::ErrorKind::NotFound, "Cache directory not found"))?;
         let target_file = step.target_file.as_ref().ok_or(Error::FlowExecutionFailed("No target file specified".to_string()))?;
@@ -222,12 +867,13 @@ impl FlowEngine {
         let package = self.render_template(package, &context)?;
 
         RemoteServerPackageResolver::new(window)
+            .with_cancellation(cancellation)
             .resolve(&package, cache_dir.to_str().unwrap())
             .await
     }
 
     /// Transfer deployment package to the target directory.
-    async fn transfer_package(&self, package: &str, target_file: &str, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+    async fn transfer_package(&self, package: &str, target_file: &str, permissions: &FilePermissions, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
         let mut cache_dir = window.app_handle().path_resolver().app_cache_dir()
             .ok_or(io::Error::new(io::ErrorKind::NotFound, "Cache directory not found"))?;
         let package = self.render_template(package, &context)?;
@@ -244,24 +890,44 @@ impl FlowEngine {
 
         let package_metadata = fs::metadata(&cache_dir).await?;
         let total_size = package_metadata.len();
-        let size = session.execute_command(
-            format!("du {target_file} | tr -s ' ' | cut -d ' ' -f 1").as_str(),
-        ).await.unwrap_or("0".into());
-        if total_size < ByteSize::mb(5).as_u64() || (ByteSize::kb(size.parse().unwrap_or(0)).as_u64() as i64 - total_size as i64).abs() > ByteSize::mb(1).as_u64() as i64 {
-            println!("Transfer package from {} to {}", package, target_file);
-            session.transfer_file(
-                dir,
-                filename,
-                &fs::read(&cache_dir).await?,
-                window,
-            ).await?;
+        let checksum = sha256_digest(fs::File::open(&cache_dir).await?).await?;
+        if session.sha256sum(&target_file).await?.as_deref() == Some(checksum.as_str()) {
+            println!("Package {} is already transferred to {}", package, target_file);
+            return session.apply_permissions(&target_file, permissions).await;
         }
 
-        Ok(())
+        println!("Transfer package from {} to {}", package, target_file);
+        session.transfer_file(
+            dir,
+            filename,
+            fs::File::open(&cache_dir).await?,
+            total_size,
+            Some(&checksum),
+            permissions,
+            window,
+        ).await
     }
 
     /// Transfer configuration file to the target directory.
-    async fn transfer_config_file(&self, source_file: &str, target_file: &str, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+    async fn transfer_config_file(&self, source_file: &str, target_file: &str, permissions: &FilePermissions, options: &FlowRunOptions, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let (target_file, config_content) = self.render_config_file(source_file, target_file, context, window).await?;
+        if options.confirm_config_changes {
+            self.confirm_config_file(&target_file, &config_content, session, window).await?;
+        }
+
+        let path_buf = PathBuf::from(&target_file);
+        let filename = path_buf.file_name().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
+        let dir = path_buf.parent().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
+
+        if let Some(backup_file) = session.replace_file(dir, filename, Cursor::new(config_content.as_bytes()), config_content.len() as u64, permissions, window).await? {
+            println!("Backup config file {} to {}", target_file, backup_file);
+        }
+
+        Ok(())
+    }
+
+    /// Render the configuration file template, returning the rendered target file and the config content.
+    async fn render_config_file(&self, source_file: &str, target_file: &str, context: &Context, window: &Window) -> Result<(String, String), Error> {
         let mut cache_dir = window.app_handle().path_resolver().app_cache_dir()
             .ok_or(io::Error::new(io::ErrorKind::NotFound, "Cache directory not found"))?;
         let source_file = self.render_template(source_file, &context)?;
@@ -278,28 +944,66 @@ impl FlowEngine {
             e
         })?;
         let target_file = self.render_template(target_file, &context)?;
-        let path_buf = PathBuf::from(&target_file);
-        let filename = path_buf.file_name().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
-        let dir = path_buf.parent().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
 
-        session.transfer_file(dir, filename, config_content.as_bytes(), window).await?;
-        Ok(())
+        Ok((target_file, config_content))
+    }
+
+    /// Create the unified diff between the current remote config file and the rendered config content,
+    /// empty if nothing changes.
+    async fn diff_config_file(&self, target_file: &str, config_content: &str, session: &mut Session) -> Result<String, Error> {
+        let current_content = session.read_file_to_string(target_file).await?.unwrap_or_default();
+        if current_content == config_content {
+            return Ok(String::new());
+        }
+
+        Ok(TextDiff::from_lines(current_content.as_str(), config_content)
+            .unified_diff()
+            .header(&format!("{target_file} (remote)"), &format!("{target_file} (rendered)"))
+            .to_string())
+    }
+
+    /// Preview the changes of the config file and wait for the operator to confirm them.
+    async fn confirm_config_file(&self, target_file: &str, config_content: &str, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let diff = self.diff_config_file(target_file, config_content, session).await?;
+        if diff.is_empty() {
+            return Ok(());
+        }
+
+        let confirmations = window.state::<Confirmations>();
+        let (request_id, receiver) = confirmations.request();
+        window.emit("config-diff-preview", ConfigDiffPreviewEvent {
+            request_id,
+            target_file,
+            diff: &diff,
+        })?;
+
+        match cancellable(session.cancellation().as_ref(), timeout(CONFIRMATION_TIMEOUT, receiver)).await {
+            Ok(Ok(Ok(true))) => Ok(()),
+            Err(e) => {
+                confirmations.discard(request_id);
+                Err(e)
+            }
+            _ => {
+                confirmations.discard(request_id);
+                Err(Error::ConfigChangeRejected(target_file.to_owned()))
+            }
+        }
     }
 
     /// Transfer file from source to target directory.
-    async fn transfer_file(&self, step: &FlowStepDefinition, args: &HashMap<String, Value>, session: &mut Session, window: &Window) -> Result<(), Error> {
+    async fn transfer_file(&self, step: &FlowStepDefinition, args: &HashMap<String, Value>, permissions: &FilePermissions, session: &mut Session, window: &Window) -> Result<(), Error> {
         if let Some(ref param_name) = step.source_file_param_name {
             if let Some(value) = args.get(param_name) {
                 match value {
                     Value::String(source_file) => {
                         let target_file = self.construct_target_file(step.target_dir.as_deref(), step.target_file.as_deref(), source_file)?;
-                        self.do_transfer_file(source_file, &target_file, session, window).await?;
+                        self.do_transfer_file(source_file, &target_file, permissions, session, window).await?;
                     }
                     Value::Array(arr) => {
                         for item in arr {
                             if let Some(source_file) = item.as_str() {
                                 let target_file = self.construct_target_file(step.target_dir.as_deref(), step.target_file.as_deref(), source_file)?;
-                                self.do_transfer_file(source_file, &target_file, session, window).await?;
+                                self.do_transfer_file(source_file, &target_file, permissions, session, window).await?;
                             }
                         }
                     }
@@ -311,6 +1015,48 @@ impl FlowEngine {
         Ok(())
     }
 
+    /// Transfer a local directory tree to the target directory.
+    /// The source directory is taken from `sourceDir` relative to the cache directory, or from the parameter named by `sourceFileParamName`.
+    /// The mode of the step applies to the files, the directories keep their local modes.
+    async fn transfer_directory(&self, step: &FlowStepDefinition, args: &HashMap<String, Value>, permissions: &FilePermissions, context: &Context, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let local_dir = match step.source_dir {
+            Some(ref source_dir) => {
+                let mut cache_dir = window.app_handle().path_resolver().app_cache_dir()
+                    .ok_or(io::Error::new(io::ErrorKind::NotFound, "Cache directory not found"))?;
+                cache_dir.push(self.render_template(source_dir, context)?);
+                cache_dir
+            }
+            None => {
+                let param_name = step.source_file_param_name.as_ref()
+                    .ok_or(Error::FlowExecutionFailed(format!("No source directory specified for step {}", step.name)))?;
+                let source_dir = args.get(param_name).and_then(Value::as_str)
+                    .ok_or(Error::FlowExecutionFailed(format!("Invalid value type for parameter {}", param_name)))?;
+                PathBuf::from(source_dir)
+            }
+        };
+        if !try_exists(&local_dir).await? {
+            return Err(Error::FlowExecutionFailed(format!("Source directory not found: {:?}", local_dir)));
+        }
+
+        let target_dir = step.target_dir.as_ref()
+            .ok_or(Error::FlowExecutionFailed(format!("No target directory specified for step {}", step.name)))?;
+        let target_dir = self.render_template(target_dir, context)?;
+
+        session.transfer_directory(&local_dir, &target_dir, permissions, step.preserve_mtime.unwrap_or(false), window).await
+    }
+
+    /// Create the file permissions of a transfer step, the owner and group are rendered as templates.
+    fn create_file_permissions(&self, step: &FlowStepDefinition, context: &Context) -> Result<FilePermissions, Error> {
+        let mode = step.mode.as_ref()
+            .map(|mode| u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .map_err(|_| Error::FlowExecutionFailed(format!("Invalid mode {} for step {}", mode, step.name))))
+            .transpose()?;
+        let owner = step.owner.as_ref().map(|owner| self.render_template(owner, context)).transpose()?;
+        let group = step.group.as_ref().map(|group| self.render_template(group, context)).transpose()?;
+
+        Ok(FilePermissions { mode, owner, group })
+    }
+
     /// Construct the target file path based on the target_dir and target_file parameters.
     /// If target_file is provided, it will be used as the target file path.
     /// If target_dir is provided, the source file name will be used as the target file name.
@@ -332,13 +1078,75 @@ impl FlowEngine {
     }
 
     /// Transfer file from source to target directory.
-    async fn do_transfer_file(&self, source_file: &str, target_file: &str, session: &mut Session, window: &Window) -> Result<(), Error> {
-        let file_content = fs::read(source_file).await?;
+    async fn do_transfer_file(&self, source_file: &str, target_file: &str, permissions: &FilePermissions, session: &mut Session, window: &Window) -> Result<(), Error> {
+        let file = fs::File::open(source_file).await?;
+        let total_size = file.metadata().await?.len();
         let path_buf = PathBuf::from(target_file);
         let filename = path_buf.file_name().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
         let dir = path_buf.parent().ok_or(Error::InvalidPath)?.to_str().ok_or(Error::InvalidPath)?;
 
-        session.transfer_file(dir, filename, &file_content, window).await?;
-        Ok(())
+        session.transfer_file(dir, filename, file, total_size, None, permissions, window).await
     }
-}
\ No newline at end of file
+
+    /// Fetch the remote file into the app data folder of the hospital.
+    /// The target file is relative to the hospital folder, the remote file name is used if absent.
+    pub async fn fetch_file(&self, hospital_id: &str, source_file: &str, target_file: Option<&str>, session: &mut Session, window: &Window) -> Result<PathBuf, Error> {
+        let mut local_file = window.app_handle().path_resolver().app_data_dir()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "Data directory not found"))?;
+        local_file.push("fetched");
+        let hospital_dir = Path::new(hospital_id);
+        if !matches!(hospital_dir.components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+            return Err(Error::InvalidPath);
+        }
+        local_file.push(hospital_dir);
+        match target_file {
+            Some(target_file) => {
+                let target_path = PathBuf::from(target_file);
+                if !target_path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+                    return Err(Error::InvalidPath);
+                }
+                local_file.push(target_path)
+            }
+            None => local_file.push(
+                PathBuf::from(source_file).file_name().ok_or(Error::InvalidFilename(source_file.to_string()))?
+            ),
+        }
+
+        println!("Fetch file from {} to {:?}", source_file, local_file);
+        session.download_file(source_file, &local_file, window).await?;
+        Ok(local_file)
+    }
+}
+
+/// Checks whether the text refers to the registered variable as `vars.<name>`, not to a longer name starting with it.
+fn uses_variable(text: &str, name: &str) -> bool {
+    let reference = format!("vars.{}", name);
+    text.match_indices(&reference).any(|(index, _)| {
+        let preceded = text[..index].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+        let followed = text[index + reference.len()..].chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_');
+        !preceded && !followed
+    })
+}
+
+/// Adds the registered variable to the `vars` of the context.
+fn register_variable(context: &mut Context, variable: RegisteredVariable) {
+    let mut variables = match context.get("vars") {
+        Some(Value::Object(variables)) => variables.clone(),
+        _ => Default::default(),
+    };
+    variables.insert(variable.name, variable.value);
+    context.insert("vars", &variables);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_variable_on_name_boundary() {
+        assert!(uses_variable("docker rm {{ vars.id }}", "id"));
+        assert!(uses_variable("{{ vars.id.name }}", "id"));
+        assert!(!uses_variable("{{ vars.identity }}", "id"));
+        assert!(!uses_variable("{{ myvars.id }}", "id"));
+    }
+}
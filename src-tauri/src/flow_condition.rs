@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::error::Error;
+use crate::session::Session;
+use crate::template::TemplateEvaluator;
+use crate::util::shell_quote;
+
+/// A condition of a flow step, evaluated natively by the engine.
+/// All values are Tera templates rendered with the context of the flow.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum StepCondition {
+    /// The remote file or directory exists.
+    FileExists { path: String },
+    /// A docker image is present, e.g. `redis` for any tag or `redis:7.2` for one tag.
+    DockerImagePresent { image: String },
+    /// A docker container with the name, or created from the image, is running.
+    ContainerRunning { name: Option<String>, image: Option<String> },
+    /// The remote command exits with zero.
+    CommandSucceeds { command: String },
+    /// A TCP port is listening on the remote server.
+    PortListening { port: String },
+    /// The Tera expression is true, e.g. `os is starting_with("ubuntu")`.
+    Expression { expression: String },
+}
+
+impl StepCondition {
+    /// Evaluates the condition, returning its rendered description and whether it holds.
+    pub async fn evaluate(&self, template_evaluator: &TemplateEvaluator, context: &Context, session: &mut Session) -> Result<(String, bool), Error> {
+        let render = |template: &str| template_evaluator.render(template, context);
+        match self {
+            StepCondition::FileExists { path } => {
+                let path = render(path)?;
+                let exists = session.file_exists(&path).await?;
+                Ok((format!("fileExists {path}"), exists))
+            }
+            StepCondition::DockerImagePresent { image } => {
+                let image = render(image)?;
+                let images = probe(session, "docker images --format '{{.Repository}}:{{.Tag}}'").await?;
+                let present = images.lines().any(|line| image_matches(line.trim(), &image));
+                Ok((format!("dockerImagePresent {image}"), present))
+            }
+            StepCondition::ContainerRunning { name, image } => {
+                if name.is_none() && image.is_none() {
+                    return Err(Error::FlowExecutionFailed("containerRunning needs a name or an image".into()));
+                }
+
+                let mut command = String::from("docker ps -q --filter status=running");
+                let mut description = String::from("containerRunning");
+                if let Some(name) = name {
+                    let name = render(name)?;
+                    command.push_str(&format!(" --filter {}", shell_quote(&format!("name=^{}$", name))));
+                    description.push_str(&format!(" {name}"));
+                }
+                if let Some(image) = image {
+                    let image = render(image)?;
+                    command.push_str(&format!(" --filter {}", shell_quote(&format!("ancestor={}", image))));
+                    description.push_str(&format!(" {image}"));
+                }
+                let running = !probe(session, &command).await?.trim().is_empty();
+                Ok((description, running))
+            }
+            StepCondition::CommandSucceeds { command } => {
+                let command = render(command)?;
+                let succeeded = session.test_command(&command).await?;
+                Ok((command, succeeded))
+            }
+            StepCondition::PortListening { port } => {
+                let port = render(port)?;
+                let port: u16 = port.trim().parse()
+                    .map_err(|_| Error::FlowExecutionFailed(format!("Invalid port: {}", port)))?;
+                let command = format!("(ss -ltnH 2>/dev/null || netstat -ltn 2>/dev/null) | awk '{{print $4}}' | grep -Eq '[:.]{port}$'");
+                let listening = session.test_command(&command).await?;
+                Ok((format!("portListening {port}"), listening))
+            }
+            StepCondition::Expression { expression } => {
+                let result = render(&format!("{{% if {expression} %}}true{{% else %}}false{{% endif %}}"))?;
+                Ok((expression.clone(), result == "true"))
+            }
+        }
+    }
+}
+
+/// Runs a probing command, a failure such as a missing docker means an empty output.
+async fn probe(session: &mut Session, command: &str) -> Result<String, Error> {
+    match session.execute_command(command).await {
+        Ok(output) => Ok(output),
+        Err(Error::CommandExecutionFailed(_)) => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks whether the `repository:tag` of an image matches the image of a condition,
+/// which matches any tag if it has none and any registry prefix of the repository.
+fn image_matches(repository_tag: &str, image: &str) -> bool {
+    let (repository, tag) = split_image(repository_tag);
+    let (expected_repository, expected_tag) = split_image(image);
+
+    let repository_matches = repository == expected_repository
+        || repository.ends_with(&format!("/{expected_repository}"));
+    repository_matches && expected_tag.map_or(true, |expected_tag| tag.unwrap_or("latest") == expected_tag)
+}
+
+/// Splits an image into its repository and tag, the colon of a registry port does not start a tag.
+fn split_image(image: &str) -> (&str, Option<&str>) {
+    match image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+        _ => (image, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_without_tag_matches_any_tag() {
+        assert!(image_matches("redis:7.2", "redis"));
+        assert!(image_matches("redis:latest", "redis"));
+    }
+
+    #[test]
+    fn image_with_tag_matches_that_tag() {
+        assert!(image_matches("redis:7.2", "redis:7.2"));
+        assert!(!image_matches("redis:7.2", "redis:7.0"));
+        assert!(image_matches("redis", "redis:latest"));
+    }
+
+    #[test]
+    fn image_matches_registry_prefix() {
+        assert!(image_matches("docker.io/library/redis:7.2", "redis"));
+        assert!(image_matches("docker.io/library/redis:7.2", "library/redis:7.2"));
+        assert!(!image_matches("docker.io/library/myredis:7.2", "redis"));
+        assert!(!image_matches("redis:7.2", "docker.io/library/redis"));
+    }
+
+    #[test]
+    fn registry_port_is_not_a_tag() {
+        assert!(image_matches("registry.local:5000/app:1.0", "registry.local:5000/app"));
+        assert!(image_matches("registry.local:5000/app:1.0", "registry.local:5000/app:1.0"));
+        assert!(image_matches("registry.local:5000/app:1.0", "app:1.0"));
+        assert!(!image_matches("registry.local:5000/app:1.0", "registry.local:5000/app:2.0"));
+        assert!(image_matches("registry.local:5000/app", "registry.local:5000/app:latest"));
+    }
+
+    #[test]
+    fn untagged_image_does_not_match() {
+        assert!(!image_matches("<none>:<none>", "redis"));
+    }
+}
@@ -0,0 +1,353 @@
+use std::time::Instant;
+
+use serde::Serialize;
+use serde_json::Value;
+use tauri::Window;
+
+use crate::error::Error;
+use crate::util::now_millis;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StepStatus {
+    Started,
+    Retrying,
+    Skipped,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FlowRunStatus {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl FlowRunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowRunStatus::Succeeded => "succeeded",
+            FlowRunStatus::Failed => "failed",
+            FlowRunStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The details of a step execution, filled in by the step handlers as they go.
+#[derive(Debug, Clone, Default)]
+pub struct StepOutput {
+    pub command: Option<String>,
+    pub exit_code: Option<u32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub registered: Option<RegisteredVariable>,
+}
+
+/// A flow variable registered from the output of a step, available to the later steps as `vars.<name>`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisteredVariable {
+    pub name: String,
+    pub value: Value,
+}
+
+/// The result of a step, emitted as `flow-step-status` event and collected into the run summary.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowStepResult {
+    pub index: usize,
+    pub name: String,
+    pub status: StepStatus,
+    pub duration_ms: Option<u64>,
+    pub command: Option<String>,
+    pub exit_code: Option<u32>,
+    pub stderr: Option<String>,
+    pub error: Option<String>,
+    /// The variable registered by the step, kept in the run history so a resumed run gets it back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registered: Option<RegisteredVariable>,
+}
+
+/// The result of a rollback or `onFailure` step, emitted as `flow-rollback-step-status` event
+/// and collected into the run summary apart from the flow steps.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollbackStepResult {
+    /// The index of the flow step rolled back, `None` for the `onFailure` steps of the flow.
+    pub step_index: Option<usize>,
+    #[serde(flatten)]
+    pub result: FlowStepResult,
+}
+
+/// The status event of a step of a nested flow, tagged with the name of the flow.
+#[derive(Debug, Clone, Serialize)]
+struct NestedStepEvent<'a, T> {
+    flow: &'a str,
+    #[serde(flatten)]
+    result: &'a T,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FlowStepChangeEvent {
+    data: usize,
+}
+
+/// The `flow-run-started` event, giving the window the id to cancel the run with.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowRunStartedEvent<'a> {
+    pub run_id: i64,
+    pub flow_name: &'a str,
+}
+
+/// The summary of a flow run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowRunSummary {
+    pub flow_name: String,
+    pub status: FlowRunStatus,
+    /// The start time in milliseconds since the unix epoch.
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    pub steps: Vec<FlowStepResult>,
+    /// The rollback and `onFailure` steps run after a step failed, in the order they ran.
+    pub rollback_steps: Vec<RollbackStepResult>,
+    pub error: Option<String>,
+    /// The full output log of the run, kept for the run history instead of being sent to the window.
+    #[serde(skip)]
+    pub log: String,
+}
+
+/// Persists the progress of a flow run after every finished step, so an interrupted run can be resumed.
+pub trait RunCheckpoint: Send + Sync {
+    fn save(&self, steps: &[FlowStepResult]) -> Result<(), Error>;
+}
+
+/// Reports the step results of a flow run to the window and collects them.
+pub struct FlowReporter<'a> {
+    window: &'a Window,
+    /// Whether the flow runs within another flow, as a `runFlow` step or as a prerequisite.
+    nested: bool,
+    checkpoint: Option<&'a dyn RunCheckpoint>,
+    flow_name: String,
+    started_at: u64,
+    started: Instant,
+    steps: Vec<FlowStepResult>,
+    rollback_steps: Vec<RollbackStepResult>,
+    log: String,
+}
+
+impl<'a> FlowReporter<'a> {
+    pub fn new(flow_name: impl Into<String>, window: &'a Window) -> Self {
+        Self { window, nested: false, checkpoint: None, flow_name: flow_name.into(), started_at: now_millis(), started: Instant::now(), steps: vec![], rollback_steps: vec![], log: String::new() }
+    }
+
+    /// Creates the reporter of a flow run within another flow, whose step events are tagged with the flow name
+    /// and which does not report the current step or the end of the run to the window.
+    pub fn nested(flow_name: impl Into<String>, window: &'a Window) -> Self {
+        Self { nested: true, ..Self::new(flow_name, window) }
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: Option<&'a dyn RunCheckpoint>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// Reports the step which is about to run as `flow-step-change` event.
+    pub fn step_changed(&self, index: usize) -> Result<(), Error> {
+        if !self.nested {
+            self.window.emit("flow-step-change", FlowStepChangeEvent { data: index })?;
+        }
+        Ok(())
+    }
+
+    /// Reports that the prerequisite flow is skipped because its readiness check holds.
+    pub fn prerequisite_ready(&mut self, flow_name: &str, condition: &str) {
+        self.log.push_str(&format!("==> [dependsOn] {} (ready: {})\n", flow_name, condition));
+    }
+
+    /// Reports the run of a prerequisite flow, appending its log.
+    pub fn prerequisite_finished(&mut self, summary: &FlowRunSummary) {
+        self.log.push_str(&format!("==> [dependsOn] {} ({})\n", summary.flow_name, summary.status.as_str()));
+        self.log.push_str(&summary.log);
+    }
+
+    /// Reports that the step started.
+    pub fn step_started(&self, index: usize, name: &str) -> Result<(), Error> {
+        self.emit(&FlowStepResult {
+            index,
+            name: name.to_owned(),
+            status: StepStatus::Started,
+            duration_ms: None,
+            command: None,
+            exit_code: None,
+            stderr: None,
+            error: None,
+            registered: None,
+        })
+    }
+
+    /// Reports that the failed attempt of the step is retried.
+    pub fn step_retrying(&mut self, index: usize, name: &str, attempt: u32, error: &Error) -> Result<(), Error> {
+        self.log.push_str(&format!("==> [{}] {} (retry {}): {}\n", index, name, attempt, error));
+        self.emit(&FlowStepResult {
+            index,
+            name: name.to_owned(),
+            status: StepStatus::Retrying,
+            duration_ms: None,
+            command: None,
+            exit_code: None,
+            stderr: None,
+            error: Some(error.to_string()),
+            registered: None,
+        })
+    }
+
+    /// Reports that the step was skipped because its condition is true, or because it completed in the resumed run
+    /// and registered the variable then.
+    pub fn step_skipped(&mut self, index: usize, name: &str, condition: Option<String>, registered: Option<RegisteredVariable>, started: Instant) -> Result<(), Error> {
+        self.log.push_str(&format!("==> [{}] {} (skipped)\n", index, name));
+        self.record(FlowStepResult {
+            index,
+            name: name.to_owned(),
+            status: StepStatus::Skipped,
+            duration_ms: Some(started.elapsed().as_millis() as u64),
+            command: condition,
+            exit_code: None,
+            stderr: None,
+            error: None,
+            registered,
+        })
+    }
+
+    /// Reports that the step finished with the result.
+    pub fn step_finished(&mut self, index: usize, name: &str, output: StepOutput, started: Instant, result: &Result<(), Error>) -> Result<(), Error> {
+        self.log.push_str(&format!("==> [{}] {}\n", index, name));
+        let result = self.finished_result(index, name, output, started, result);
+        self.record(result)
+    }
+
+    /// Reports that the failed attempt of the rollback step is retried.
+    pub fn rollback_step_retrying(&mut self, name: &str, attempt: u32, error: &Error) {
+        self.log.push_str(&format!("<== [rollback] {} (retry {}): {}\n", name, attempt, error));
+    }
+
+    /// Reports that the rollback step of the flow step, or the `onFailure` step if `step_index` is `None`, was skipped.
+    pub fn rollback_step_skipped(&mut self, step_index: Option<usize>, name: &str, condition: String, started: Instant) {
+        self.log.push_str(&format!("<== [rollback] {} (skipped)\n", name));
+        self.record_rollback(step_index, FlowStepResult {
+            index: self.rollback_steps.len(),
+            name: name.to_owned(),
+            status: StepStatus::Skipped,
+            duration_ms: Some(started.elapsed().as_millis() as u64),
+            command: Some(condition),
+            exit_code: None,
+            stderr: None,
+            error: None,
+            registered: None,
+        });
+    }
+
+    /// Reports that the rollback step of the flow step, or the `onFailure` step if `step_index` is `None`, finished.
+    pub fn rollback_step_finished(&mut self, step_index: Option<usize>, name: &str, output: StepOutput, started: Instant, result: &Result<(), Error>) {
+        self.log.push_str(&format!("<== [rollback] {}\n", name));
+        let result = self.finished_result(self.rollback_steps.len(), name, output, started, result);
+        self.record_rollback(step_index, result);
+    }
+
+    /// Appends the output of the finished step to the log and creates its result.
+    fn finished_result(&mut self, index: usize, name: &str, output: StepOutput, started: Instant, result: &Result<(), Error>) -> FlowStepResult {
+        let (status, error) = match result {
+            Ok(_) => (StepStatus::Succeeded, None),
+            Err(e @ Error::Cancelled) => (StepStatus::Cancelled, Some(e.to_string())),
+            Err(e) => (StepStatus::Failed, Some(e.to_string())),
+        };
+
+        if let Some(ref command) = output.command {
+            self.log.push_str(&format!("$ {}\n", command));
+        }
+        for text in [&output.stdout, &output.stderr].into_iter().flatten() {
+            self.log.push_str(text);
+            if !text.ends_with('\n') {
+                self.log.push('\n');
+            }
+        }
+        if let Some(ref error) = error {
+            self.log.push_str(&format!("!! {}\n", error));
+        }
+
+        FlowStepResult {
+            index,
+            name: name.to_owned(),
+            status,
+            duration_ms: Some(started.elapsed().as_millis() as u64),
+            command: output.command,
+            exit_code: output.exit_code,
+            stderr: output.stderr,
+            error,
+            registered: output.registered,
+        }
+    }
+
+    /// Finishes the run, emitting the `flow-run-finished` event with the summary.
+    pub fn finish(self, error: Option<&Error>) -> FlowRunSummary {
+        let summary = FlowRunSummary {
+            flow_name: self.flow_name,
+            status: match error {
+                None => FlowRunStatus::Succeeded,
+                Some(Error::Cancelled) => FlowRunStatus::Cancelled,
+                Some(_) => FlowRunStatus::Failed,
+            },
+            started_at: self.started_at,
+            finished_at: now_millis(),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            steps: self.steps,
+            rollback_steps: self.rollback_steps,
+            error: error.map(Error::to_string),
+            log: self.log,
+        };
+
+        if !self.nested {
+            if let Err(e) = self.window.emit("flow-run-finished", &summary) {
+                println!("Failed to emit flow run summary: {:?}", e);
+            }
+        }
+        summary
+    }
+
+    fn record(&mut self, result: FlowStepResult) -> Result<(), Error> {
+        self.emit(&result)?;
+        self.steps.push(result);
+        if let Some(checkpoint) = self.checkpoint {
+            if let Err(e) = checkpoint.save(&self.steps) {
+                println!("Failed to save flow run checkpoint: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn record_rollback(&mut self, step_index: Option<usize>, result: FlowStepResult) {
+        let result = RollbackStepResult { step_index, result };
+        let emitted = if self.nested {
+            self.window.emit("flow-rollback-step-status", NestedStepEvent { flow: &self.flow_name, result: &result })
+        } else {
+            self.window.emit("flow-rollback-step-status", &result)
+        };
+        if let Err(e) = emitted {
+            println!("Failed to emit rollback step status: {:?}", e);
+        }
+        self.rollback_steps.push(result);
+    }
+
+    fn emit(&self, result: &FlowStepResult) -> Result<(), Error> {
+        if self.nested {
+            self.window.emit("flow-step-status", NestedStepEvent { flow: &self.flow_name, result })?;
+        } else {
+            self.window.emit("flow-step-status", result)?;
+        }
+        Ok(())
+    }
+}
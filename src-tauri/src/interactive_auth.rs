@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use russh::client::Prompt;
+use serde::Serialize;
+use tauri::Window;
+use tokio::time::timeout;
+
+use crate::error::Error;
+use crate::pending_requests::PendingRequests;
+
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyboardInteractivePromptEvent<'a> {
+    request_id: u64,
+    name: &'a str,
+    instructions: &'a str,
+    prompts: Vec<PromptItem<'a>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptItem<'a> {
+    prompt: &'a str,
+    echo: bool,
+}
+
+/// Forwards keyboard-interactive prompts to the window and waits for the operator's answers.
+#[derive(Debug, Default)]
+pub struct InteractivePrompter {
+    requests: PendingRequests<Option<Vec<String>>>,
+}
+
+impl InteractivePrompter {
+    /// Answers the prompts, password prompts are answered with the known password if there is one.
+    pub async fn ask(&self, name: &str, instructions: &str, prompts: &[Prompt], password: Option<&str>, window: &Window) -> Result<Vec<String>, Error> {
+        let mut answers: Vec<Option<String>> = prompts.iter()
+            .map(|prompt| password.filter(|_| is_password_prompt(prompt)).map(str::to_owned))
+            .collect();
+        if answers.iter().all(Option::is_some) {
+            return Ok(answers.into_iter().flatten().collect());
+        }
+
+        let (request_id, receiver) = self.requests.request();
+
+        window.emit("keyboard-interactive-prompt", KeyboardInteractivePromptEvent {
+            request_id,
+            name,
+            instructions,
+            prompts: prompts.iter()
+                .zip(&answers)
+                .filter(|(_, answer)| answer.is_none())
+                .map(|(prompt, _)| PromptItem { prompt: &prompt.prompt, echo: prompt.echo })
+                .collect(),
+        })?;
+
+        let responses = match timeout(ANSWER_TIMEOUT, receiver).await {
+            Ok(Ok(Some(responses))) => responses,
+            _ => {
+                self.requests.discard(request_id);
+                return Err(Error::AuthenticationCancelled);
+            }
+        };
+
+        let mut responses = responses.into_iter();
+        for answer in answers.iter_mut().filter(|answer| answer.is_none()) {
+            *answer = Some(responses.next().unwrap_or_default());
+        }
+
+        Ok(answers.into_iter().flatten().collect())
+    }
+
+    /// Delivers the operator's answers of a prompt request, `None` cancels the authentication.
+    pub fn respond(&self, request_id: u64, responses: Option<Vec<String>>) -> Result<(), Error> {
+        if self.requests.respond(request_id, responses) {
+            Ok(())
+        } else {
+            Err(Error::AuthenticationCancelled)
+        }
+    }
+}
+
+fn is_password_prompt(prompt: &Prompt) -> bool {
+    !prompt.echo && prompt.prompt.to_lowercase().contains("password")
+}
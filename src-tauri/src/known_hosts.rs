@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::error::Error;
+use crate::util::now_millis;
+
+/// A server host key pinned on first use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownHost {
+    pub server_id: String,
+    pub host: String,
+    pub port: u16,
+    pub algorithm: String,
+    pub fingerprint: String,
+    pub added_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KnownHostsData {
+    hosts: HashMap<String, KnownHost>,
+    /// Host keys which were rejected because they differ from the pinned ones, waiting for the operator to accept them.
+    pending: HashMap<String, KnownHost>,
+}
+
+/// The app-managed known hosts store, keyed by server id.
+#[derive(Debug, Clone)]
+pub struct KnownHosts {
+    file: PathBuf,
+    data: Arc<Mutex<KnownHostsData>>,
+}
+
+impl KnownHosts {
+    /// Loads the known hosts store from the file, an absent file results in an empty store.
+    /// An unreadable or corrupt file fails instead of trusting every server again.
+    pub fn load(file: PathBuf) -> Result<Self, Error> {
+        let data = match fs::read(&file) {
+            Ok(content) => serde_json::from_slice(&content).map_err(std::io::Error::from)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => KnownHostsData::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { file, data: Arc::new(Mutex::new(data)) })
+    }
+
+    /// Verifies the host key presented by the server, pinning it if the server is seen for the first time.
+    pub async fn verify(&self, server_id: &str, host: &str, port: u16, algorithm: &str, fingerprint: &str) -> Result<(), Error> {
+        let mut data = self.data.lock().await;
+        let presented = KnownHost {
+            server_id: server_id.to_owned(),
+            host: host.to_owned(),
+            port,
+            algorithm: algorithm.to_owned(),
+            fingerprint: fingerprint.to_owned(),
+            added_at: now_millis() / 1000,
+        };
+
+        match data.hosts.get(server_id) {
+            Some(known_host) if known_host.fingerprint == fingerprint => Ok(()),
+            Some(_) => {
+                data.pending.insert(server_id.to_owned(), presented);
+                self.save(&data).await?;
+                Err(Error::HostKeyMismatch(fingerprint.to_owned()))
+            }
+            None => {
+                println!("Pinning host key of server {}: {} {}", server_id, algorithm, fingerprint);
+                data.hosts.insert(server_id.to_owned(), presented);
+                self.save(&data).await
+            }
+        }
+    }
+
+    /// Lists all pinned host keys.
+    pub async fn list(&self) -> Vec<KnownHost> {
+        self.data.lock().await.hosts.values().cloned().collect()
+    }
+
+    /// Gets the host key which was rejected for the server, if any.
+    pub async fn get_pending(&self, server_id: &str) -> Option<KnownHost> {
+        self.data.lock().await.pending.get(server_id).cloned()
+    }
+
+    /// Accepts the rejected host key of the server, replacing the pinned one.
+    /// The fingerprint must match the rejected one to make sure the operator reviewed the right key.
+    pub async fn accept(&self, server_id: &str, fingerprint: &str) -> Result<(), Error> {
+        let mut data = self.data.lock().await;
+        let reviewed = data.pending.get(server_id).is_some_and(|known_host| known_host.fingerprint == fingerprint);
+        if !reviewed {
+            return Err(Error::HostKeyNotFound(server_id.to_owned()));
+        }
+
+        if let Some(known_host) = data.pending.remove(server_id) {
+            data.hosts.insert(server_id.to_owned(), known_host);
+        }
+        self.save(&data).await
+    }
+
+    /// Revokes the pinned host key of the server, the next connection will pin the key again.
+    pub async fn revoke(&self, server_id: &str) -> Result<(), Error> {
+        let mut data = self.data.lock().await;
+        data.hosts.remove(server_id);
+        data.pending.remove(server_id);
+        self.save(&data).await
+    }
+
+    /// Saves the store to a temporary sibling file which is renamed over the file,
+    /// so an interrupted save never leaves a truncated file.
+    async fn save(&self, data: &KnownHostsData) -> Result<(), Error> {
+        if let Some(parent) = self.file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let content = serde_json::to_vec_pretty(data).map_err(std::io::Error::from)?;
+        let mut temp_file = self.file.as_os_str().to_owned();
+        temp_file.push(".tmp");
+        tokio::fs::write(&temp_file, content).await?;
+        tokio::fs::rename(&temp_file, &self.file).await?;
+        Ok(())
+    }
+}
@@ -1,11 +1,35 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use tauri::{Manager, RunEvent};
+
+use command::accept_host_key;
+use command::cancel_command;
+use command::cancel_flow;
+use command::confirm_config_changes;
 use command::execute_command;
 use command::execute_command_stream;
 use command::execute_flow;
+use command::export_run_history;
+use command::fetch_file;
+use command::list_config_backups;
+use command::get_pending_host_key;
+use command::get_run_record;
 use command::list_flows;
+use command::list_known_hosts;
+use command::list_run_history;
+use command::plan_flow;
+use command::respond_keyboard_interactive;
+use command::resume_flow;
+use command::restore_config_backup;
+use command::revoke_host_key;
 use command::test_ssh_connection;
+use cancellation::RunCancellations;
+use interactive_auth::InteractivePrompter;
+use known_hosts::KnownHosts;
+use pending_requests::Confirmations;
+use run_history::RunHistory;
+use session_pool::SessionPool;
 
 mod error;
 mod client;
@@ -23,19 +47,59 @@ mod file_downloader;
 mod template;
 mod util;
 mod decompressor;
+mod known_hosts;
+mod interactive_auth;
+mod session_pool;
+mod pending_requests;
+mod flow_report;
+mod run_history;
+mod cancellation;
+mod flow_condition;
 
 fn main() {
     tauri::Builder::default()
+        .setup(|app| {
+            let data_dir = app.path_resolver().app_data_dir().expect("Failed to resolve app data directory");
+            app.manage(KnownHosts::load(data_dir.join("known_hosts.json"))?);
+            app.manage(InteractivePrompter::default());
+            app.manage(Confirmations::default());
+            app.manage(SessionPool::default());
+            app.manage(RunCancellations::default());
+            app.manage(RunHistory::open(&data_dir.join("history.db"))?);
+            Ok(())
+        })
         .invoke_handler(
             tauri::generate_handler!(
                 test_ssh_connection,
                 execute_command,
                 execute_command_stream,
+                cancel_command,
                 list_flows,
-                execute_flow
+                execute_flow,
+                resume_flow,
+                cancel_flow,
+                plan_flow,
+                list_run_history,
+                get_run_record,
+                export_run_history,
+                fetch_file,
+                list_config_backups,
+                restore_config_backup,
+                confirm_config_changes,
+                list_known_hosts,
+                get_pending_host_key,
+                accept_host_key,
+                revoke_host_key,
+                respond_keyboard_interactive
             )
         )
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                app.state::<RunCancellations>().cancel_all();
+                tauri::async_runtime::block_on(app.state::<SessionPool>().close_all());
+            }
+        });
 }
 
@@ -4,6 +4,7 @@ use async_trait::async_trait;
 use tauri::Window;
 
 use crate::{error::Error, file_downloader::FileDownloader};
+use crate::cancellation::CancellationToken;
 use crate::util::copy_file;
 
 const BASE_REMOTE_PACKAGE_URL: &str = "http://192.168.10.207:5959/packages/";
@@ -23,6 +24,11 @@ impl<'a> RemoteServerPackageResolver<'a> {
             file_downloader: FileDownloader::new_with_event_progress_reporter(window)
         }
     }
+
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.file_downloader = self.file_downloader.with_cancellation(cancellation);
+        self
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::oneshot;
+
+/// Requests sent to the window which wait for the operator's answer, keyed by request id.
+#[derive(Debug)]
+pub struct PendingRequests<T> {
+    next_request_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<T>>>,
+}
+
+/// The config diff previews waiting for the operator to confirm or reject them.
+pub type Confirmations = PendingRequests<bool>;
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        Self { next_request_id: AtomicU64::new(0), pending: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<T> PendingRequests<T> {
+    /// Registers a request, returning its id and the receiver of the answer.
+    pub fn request(&self) -> (u64, oneshot::Receiver<T>) {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, sender);
+        (request_id, receiver)
+    }
+
+    /// Discards a request which is no longer waited for.
+    pub fn discard(&self, request_id: u64) {
+        self.pending.lock().unwrap().remove(&request_id);
+    }
+
+    /// Delivers the operator's answer of a request, returning whether the request was pending.
+    pub fn respond(&self, request_id: u64, answer: T) -> bool {
+        match self.pending.lock().unwrap().remove(&request_id) {
+            Some(sender) => {
+                let _ = sender.send(answer);
+                true
+            }
+            None => false,
+        }
+    }
+}
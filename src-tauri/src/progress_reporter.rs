@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 use bytesize::ByteSize;
 use serde::Serialize;
@@ -11,6 +13,9 @@ pub struct ProgressEvent {
     pub processed_size: usize,
     pub processed_size_format: String,
     pub progress_percent: f64,
+    /// The bytes processed per second, only reported by transfers which measure it.
+    pub throughput: Option<u64>,
+    pub throughput_format: Option<String>,
 }
 
 impl ProgressEvent {
@@ -22,8 +27,18 @@ impl ProgressEvent {
             processed_size,
             processed_size_format: ByteSize::b(processed_size as u64).to_string_as(false),
             progress_percent,
+            throughput: None,
+            throughput_format: None,
         }
     }
+
+    /// Attaches the throughput achieved by processing the bytes in the elapsed time.
+    pub fn with_throughput(mut self, bytes: usize, elapsed: Duration) -> Self {
+        let throughput = (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        self.throughput = Some(throughput);
+        self.throughput_format = Some(format!("{}/s", ByteSize::b(throughput).to_string_as(false)));
+        self
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,404 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter, Row};
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::flow_report::{FlowRunSummary, FlowStepResult, RunCheckpoint};
+use crate::hospital_settings::HospitalSettings;
+use crate::server_settings::ServerSettings;
+use crate::util::now_millis;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS flow_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    flow_name TEXT NOT NULL,
+    server_id TEXT NOT NULL,
+    server_name TEXT NOT NULL,
+    hospital_id TEXT NOT NULL,
+    hospital_name TEXT NOT NULL,
+    hospital_settings TEXT NOT NULL,
+    args TEXT NOT NULL,
+    operator TEXT NOT NULL,
+    status TEXT NOT NULL,
+    steps TEXT NOT NULL DEFAULT '[]',
+    rollback_steps TEXT NOT NULL DEFAULT '[]',
+    log TEXT NOT NULL DEFAULT '',
+    error TEXT,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_flow_runs_server_flow ON flow_runs (server_id, flow_name);
+CREATE INDEX IF NOT EXISTS idx_flow_runs_started_at ON flow_runs (started_at);
+";
+
+const RECORD_COLUMNS: &str = "id, flow_name, server_id, server_name, hospital_id, hospital_name, hospital_settings, args, operator, status, steps, error, started_at, finished_at, rollback_steps";
+
+/// A recorded flow run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRecord {
+    pub id: i64,
+    pub flow_name: String,
+    pub server_id: String,
+    pub server_name: String,
+    pub hospital_id: String,
+    pub hospital_name: String,
+    pub hospital_settings: Value,
+    pub args: Value,
+    /// The local user who ran the flow.
+    pub operator: String,
+    /// One of `running`, `succeeded`, `failed` and `cancelled`.
+    pub status: String,
+    pub steps: Value,
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub rollback_steps: Value,
+    /// The full output log, only loaded for a single record or an export.
+    pub log: Option<String>,
+}
+
+/// The filter of run history queries, absent fields do not filter.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunHistoryFilter {
+    pub flow_name: Option<String>,
+    pub server_id: Option<String>,
+    pub hospital_id: Option<String>,
+    pub status: Option<String>,
+    /// The lower bound of the start time in milliseconds since the unix epoch.
+    pub started_from: Option<i64>,
+    pub started_to: Option<i64>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// The persistent history of flow runs, stored in a SQLite database in the app data directory.
+pub struct RunHistory {
+    connection: Mutex<Connection>,
+}
+
+impl RunHistory {
+    /// Opens the run history database, creating it if needed.
+    pub fn open(file: &Path) -> Result<Self, Error> {
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(file)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    /// Records the start of a flow run, returning the id of the record.
+    pub fn start_run(&self, flow_name: &str, server_settings: &ServerSettings, hospital_settings: &HospitalSettings, args: &HashMap<String, Value>) -> Result<i64, Error> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO flow_runs (flow_name, server_id, server_name, hospital_id, hospital_name, hospital_settings, args, operator, status, started_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'running', ?9)",
+            params![
+                flow_name,
+                server_settings.id,
+                server_settings.name,
+                hospital_settings.id,
+                hospital_settings.name,
+                to_json(hospital_settings)?,
+                to_json(args)?,
+                operator(),
+                now_millis() as i64,
+            ],
+        )?;
+
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// Records the end of a flow run.
+    pub fn finish_run(&self, id: i64, summary: &FlowRunSummary) -> Result<(), Error> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE flow_runs SET status = ?1, steps = ?2, rollback_steps = ?3, log = ?4, error = ?5, finished_at = ?6 WHERE id = ?7",
+            params![
+                summary.status.as_str(),
+                to_json(&summary.steps)?,
+                to_json(&summary.rollback_steps)?,
+                summary.log,
+                summary.error,
+                summary.finished_at as i64,
+                id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Gets the checkpoint persisting the progress of the run.
+    pub fn checkpoint(&self, id: i64) -> HistoryCheckpoint<'_> {
+        HistoryCheckpoint { history: self, id }
+    }
+
+    /// Gets the latest run record of the flow on the server with its log.
+    pub fn latest_run(&self, server_id: &str, flow_name: &str) -> Result<Option<RunRecord>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let record = connection.query_row(
+            &format!("SELECT {RECORD_COLUMNS}, log FROM flow_runs WHERE server_id = ?1 AND flow_name = ?2 ORDER BY started_at DESC, id DESC LIMIT 1"),
+            params![server_id, flow_name],
+            |row| read_record(row, true),
+        ).optional()?;
+        Ok(record)
+    }
+
+    /// Queries the run records matching the filter, newest first, without their logs.
+    pub fn query(&self, filter: &RunHistoryFilter) -> Result<Vec<RunRecord>, Error> {
+        self.select(filter, false)
+    }
+
+    /// Gets the run record with its log.
+    pub fn get(&self, id: i64) -> Result<Option<RunRecord>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let record = connection.query_row(
+            &format!("SELECT {RECORD_COLUMNS}, log FROM flow_runs WHERE id = ?1"),
+            params![id],
+            |row| read_record(row, true),
+        ).optional()?;
+        Ok(record)
+    }
+
+    /// Exports the run records matching the filter with their logs as a JSON file.
+    pub fn export(&self, filter: &RunHistoryFilter, file: &Path) -> Result<(), Error> {
+        let records = self.select(filter, true)?;
+        let content = serde_json::to_vec_pretty(&records).map_err(std::io::Error::from)?;
+        fs::write(file, content)?;
+        Ok(())
+    }
+
+    fn select(&self, filter: &RunHistoryFilter, with_log: bool) -> Result<Vec<RunRecord>, Error> {
+        let mut conditions = vec![];
+        let mut values: Vec<SqlValue> = vec![];
+        let mut add = |column: &str, operator: &str, value: SqlValue| {
+            values.push(value);
+            conditions.push(format!("{column} {operator} ?{}", values.len()));
+        };
+        if let Some(ref flow_name) = filter.flow_name {
+            add("flow_name", "=", flow_name.clone().into());
+        }
+        if let Some(ref server_id) = filter.server_id {
+            add("server_id", "=", server_id.clone().into());
+        }
+        if let Some(ref hospital_id) = filter.hospital_id {
+            add("hospital_id", "=", hospital_id.clone().into());
+        }
+        if let Some(ref status) = filter.status {
+            add("status", "=", status.clone().into());
+        }
+        if let Some(started_from) = filter.started_from {
+            add("started_at", ">=", started_from.into());
+        }
+        if let Some(started_to) = filter.started_to {
+            add("started_at", "<=", started_to.into());
+        }
+
+        let mut sql = format!("SELECT {RECORD_COLUMNS}{} FROM flow_runs", if with_log { ", log" } else { "" });
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY started_at DESC, id DESC");
+        sql.push_str(&format!(" LIMIT {} OFFSET {}", filter.limit.map(i64::from).unwrap_or(-1), filter.offset.unwrap_or(0)));
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&sql)?;
+        let records = statement.query_map(params_from_iter(values), |row| read_record(row, with_log))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    }
+}
+
+impl RunRecord {
+    /// Checks whether the run can be resumed, which is if it failed, was cancelled or was interrupted
+    /// and is left `running`.
+    pub fn is_resumable(&self) -> bool {
+        self.status != "succeeded"
+    }
+
+    /// Gets the index of the step to resume the run at: the failed or cancelled step,
+    /// or the step after the last finished one if the run was interrupted.
+    /// The earliest step rolled back is resumed at instead, since it is undone.
+    pub fn resume_index(&self) -> usize {
+        let steps = self.steps.as_array().map(Vec::as_slice).unwrap_or_default();
+        let index_of = |step: &Value| step.get("index").and_then(Value::as_u64).map(|index| index as usize);
+        let resume_index = steps.iter()
+            .find(|step| matches!(step.get("status").and_then(Value::as_str), Some("failed" | "cancelled")))
+            .and_then(index_of)
+            .or_else(|| steps.iter().filter_map(index_of).max().map(|index| index + 1))
+            .unwrap_or(0);
+
+        self.rollback_steps.as_array().map(Vec::as_slice).unwrap_or_default()
+            .iter()
+            .filter_map(|step| step.get("stepIndex").and_then(Value::as_u64).map(|index| index as usize))
+            .fold(resume_index, cmp::min)
+    }
+
+    /// Gets the variables registered by the steps of the run before the given step index,
+    /// excluding the steps which were rolled back.
+    pub fn variables(&self, before: usize) -> HashMap<String, Value> {
+        let rolled_back: Vec<u64> = self.rollback_steps.as_array().map(Vec::as_slice).unwrap_or_default()
+            .iter()
+            .filter_map(|step| step.get("stepIndex").and_then(Value::as_u64))
+            .collect();
+        self.steps.as_array().map(Vec::as_slice).unwrap_or_default()
+            .iter()
+            .filter(|step| step.get("index").and_then(Value::as_u64)
+                .is_some_and(|index| (index as usize) < before && !rolled_back.contains(&index)))
+            .filter_map(|step| step.get("registered"))
+            .filter_map(|registered| Some((registered.get("name")?.as_str()?.to_owned(), registered.get("value")?.clone())))
+            .collect()
+    }
+}
+
+/// Persists the step results of a run to its record.
+pub struct HistoryCheckpoint<'a> {
+    history: &'a RunHistory,
+    id: i64,
+}
+
+impl<'a> RunCheckpoint for HistoryCheckpoint<'a> {
+    fn save(&self, steps: &[FlowStepResult]) -> Result<(), Error> {
+        self.history.connection.lock().unwrap().execute(
+            "UPDATE flow_runs SET steps = ?1 WHERE id = ?2",
+            params![to_json(steps)?, self.id],
+        )?;
+        Ok(())
+    }
+}
+
+fn read_record(row: &Row, with_log: bool) -> rusqlite::Result<RunRecord> {
+    Ok(RunRecord {
+        id: row.get(0)?,
+        flow_name: row.get(1)?,
+        server_id: row.get(2)?,
+        server_name: row.get(3)?,
+        hospital_id: row.get(4)?,
+        hospital_name: row.get(5)?,
+        hospital_settings: from_json(row.get(6)?),
+        args: from_json(row.get(7)?),
+        operator: row.get(8)?,
+        status: row.get(9)?,
+        steps: from_json(row.get(10)?),
+        error: row.get(11)?,
+        started_at: row.get(12)?,
+        finished_at: row.get(13)?,
+        rollback_steps: from_json(row.get(14)?),
+        log: if with_log { row.get(15)? } else { None },
+    })
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    Ok(serde_json::to_string(value).map_err(std::io::Error::from)?)
+}
+
+fn from_json(content: String) -> Value {
+    serde_json::from_str(&content).unwrap_or(Value::Null)
+}
+
+/// Gets the name of the local user running the app.
+fn operator() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn record(status: &str, steps: Value, rollback_steps: Value) -> RunRecord {
+        RunRecord {
+            id: 1,
+            flow_name: "deploy".into(),
+            server_id: "server".into(),
+            server_name: "server".into(),
+            hospital_id: "hospital".into(),
+            hospital_name: "hospital".into(),
+            hospital_settings: Value::Null,
+            args: Value::Null,
+            operator: String::new(),
+            status: status.into(),
+            steps,
+            error: None,
+            started_at: 0,
+            finished_at: None,
+            rollback_steps,
+            log: None,
+        }
+    }
+
+    fn registered(index: usize, status: &str, name: &str) -> Value {
+        json!({ "index": index, "status": status, "registered": { "name": name, "value": name } })
+    }
+
+    #[test]
+    fn resumes_at_failed_step() {
+        let record = record("failed", json!([
+            { "index": 0, "status": "succeeded" },
+            { "index": 1, "status": "skipped" },
+            { "index": 2, "status": "failed" },
+        ]), json!([]));
+        assert_eq!(record.resume_index(), 2);
+    }
+
+    #[test]
+    fn resumes_interrupted_run_after_last_step() {
+        let interrupted = record("running", json!([
+            { "index": 0, "status": "succeeded" },
+            { "index": 1, "status": "succeeded" },
+        ]), json!([]));
+        assert_eq!(interrupted.resume_index(), 2);
+        assert_eq!(record("running", json!([]), json!([])).resume_index(), 0);
+    }
+
+    #[test]
+    fn resumes_at_earliest_rolled_back_step() {
+        let record = record("failed", json!([
+            { "index": 0, "status": "succeeded" },
+            { "index": 1, "status": "succeeded" },
+            { "index": 2, "status": "cancelled" },
+        ]), json!([
+            { "stepIndex": 1, "index": 0, "status": "succeeded" },
+            { "stepIndex": null, "index": 1, "status": "succeeded" },
+        ]));
+        assert_eq!(record.resume_index(), 1);
+    }
+
+    #[test]
+    fn drops_variables_from_resume_index_on() {
+        let record = record("failed", json!([
+            registered(0, "succeeded", "a"),
+            registered(1, "succeeded", "b"),
+            registered(2, "failed", "c"),
+        ]), json!([]));
+        let variables = record.variables(record.resume_index());
+        assert_eq!(variables.len(), 2);
+        assert!(variables.contains_key("a") && variables.contains_key("b"));
+    }
+
+    #[test]
+    fn drops_variables_of_rolled_back_steps() {
+        let record = record("failed", json!([
+            registered(0, "succeeded", "a"),
+            registered(1, "succeeded", "b"),
+            registered(2, "failed", "c"),
+        ]), json!([
+            { "stepIndex": 0, "index": 0, "status": "succeeded" },
+        ]));
+        assert_eq!(record.resume_index(), 0);
+        assert!(record.variables(record.resume_index()).is_empty());
+        assert_eq!(record.variables(2).keys().collect::<Vec<_>>(), vec!["b"]);
+    }
+}
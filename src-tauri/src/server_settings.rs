@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::file_transfer::TransferOptions;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServerSettings {
     pub id: String,
@@ -8,5 +10,64 @@ pub struct ServerSettings {
     pub host: String,
     pub port: u16,
     pub username: String,
+    #[serde(default)]
     pub password: String,
-}
\ No newline at end of file
+    pub auth_method: Option<AuthMethod>,
+    /// The jump hosts to tunnel through in order, the last one connects to the server.
+    #[serde(default)]
+    pub jump_hosts: Vec<JumpHost>,
+    /// The tuning of SFTP uploads to the server, the defaults are used if absent.
+    pub transfer_options: Option<TransferOptions>,
+}
+
+/// A jump host (bastion) on the way to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JumpHost {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    pub auth_method: Option<AuthMethod>,
+}
+
+/// The way to authenticate against the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AuthMethod {
+    /// Authenticates with a password.
+    Password {
+        password: String,
+    },
+    /// Authenticates with a private key file in OpenSSH or PEM format.
+    #[serde(rename_all = "camelCase")]
+    PrivateKey {
+        private_key_file: String,
+        passphrase: Option<String>,
+    },
+    /// Authenticates with the identities of a local ssh-agent. The socket is a unix socket, `SSH_AUTH_SOCK` by default,
+    /// or a named pipe on Windows, the OpenSSH agent pipe by default.
+    Agent {
+        socket: Option<String>,
+    },
+}
+
+impl ServerSettings {
+    /// Gets the auth method, falling back to password authentication for settings without one.
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method.clone().unwrap_or_else(|| AuthMethod::Password {
+            password: self.password.clone(),
+        })
+    }
+}
+
+impl JumpHost {
+    /// Gets the auth method, falling back to password authentication for settings without one.
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method.clone().unwrap_or_else(|| AuthMethod::Password {
+            password: self.password.clone(),
+        })
+    }
+}
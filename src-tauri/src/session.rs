@@ -1,19 +1,89 @@
-use std::{sync::Arc, time::Duration};
-use std::path::PathBuf;
+use std::{cmp, sync::Arc, time::{Duration, UNIX_EPOCH}};
+use std::fs::Metadata;
+use std::future::Future;
+use std::io;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 
 use path_slash::PathBufExt;
-use russh::{ChannelMsg, client::{self, Handle}, Disconnect};
+use russh::{Channel, ChannelMsg, client::{self, Handle, KeyboardInteractiveAuthResponse, Prompt}, Disconnect, keys, Sig};
+#[cfg(unix)]
+use russh::keys::agent::client::AgentClient;
 use russh_sftp::client::SftpSession;
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::protocol::FileAttributes;
 use serde::Serialize;
 use tauri::Window;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite};
 use tokio::net::ToSocketAddrs;
 
 use crate::{client::Client, error::Error};
-use crate::file_transfer::FileTransfer;
+use crate::cancellation::CancellationToken;
+use crate::file_transfer::{FileTransfer, TransferOptions};
+use crate::util::{sha256_digest, shell_quote};
+
+/// The named pipe of the Windows OpenSSH agent.
+#[cfg(windows)]
+const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// The size of the tail of the stderr of a streamed command which is kept.
+const STDERR_TAIL_SIZE: usize = 64 * 1024;
+
+/// The size of the tail of the stdout of a streamed command which is kept unless the whole stdout is captured.
+const STDOUT_TAIL_SIZE: usize = 64 * 1024;
+
+/// The size of the tail of a partial remote file whose hash is compared before resuming an upload.
+const RESUME_TAIL_SIZE: u64 = 1024 * 1024;
 
 /// A session to a remote SSH server.
+/// The connection is shared by the sessions created with `share`, each of them opens its own channels.
 pub struct Session {
-    session: Handle<Client>,
+    session: Arc<Handle<Client>>,
+    /// The sessions of the jump hosts this session is tunneled through, from the first hop to the last.
+    jumps: Vec<Arc<Handle<Client>>>,
+    /// The SFTP subsystem, created on first use and shared by all transfers.
+    sftp: Option<SftpSession>,
+    transfer_options: TransferOptions,
+    /// The cancellation token of the run using the session, which aborts its commands and transfers.
+    cancellation: Option<CancellationToken>,
+}
+
+/// The mode and ownership applied to transferred remote files, absent values are left unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct FilePermissions {
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+/// A timestamped backup of a remote file.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileBackup {
+    pub file: String,
+    /// The time of the backup formatted as `%Y%m%d%H%M%S`, with a `-<n>` suffix for further backups in the same second.
+    pub timestamp: String,
+}
+
+/// The exit code and the captured output of a command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub exit_code: Option<u32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Checks that the command exited successfully.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.exit_code {
+            None => Err(Error::CommandExecutionTimeout),
+            Some(0) => Ok(()),
+            Some(_) if self.stderr.is_empty() => Err(Error::CommandExecutionFailed("未知错误".into())),
+            Some(_) => Err(Error::CommandExecutionFailed(self.stderr.clone())),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -22,25 +92,155 @@ struct PartialResult<'a> {
 }
 
 impl Session {
-    /// Connects to a remote SSH server.
-    pub async fn connect<A: ToSocketAddrs>(
-        username: impl Into<String>,
-        password: impl Into<String>,
-        address: A,
-    ) -> Result<Self, Error> {
+    /// Connects to a remote SSH server, the session must be authenticated before use.
+    pub async fn connect<A: ToSocketAddrs>(address: A, client: Client) -> Result<Self, Error> {
+        let session = Arc::new(client::connect(Self::config(), address, client).await?);
+
+        Ok(Self { session, jumps: vec![], sftp: None, transfer_options: TransferOptions::default(), cancellation: None })
+    }
+
+    /// Connects to a remote SSH server through a `direct-tcpip` channel of the jump host session,
+    /// the session must be authenticated before use.
+    pub async fn connect_via(jump: Session, host: &str, port: u16, client: Client) -> Result<Self, Error> {
+        let channel = jump.session.channel_open_direct_tcpip(host, port as u32, "127.0.0.1", 0).await?;
+        let session = Arc::new(client::connect_stream(Self::config(), channel.into_stream(), client).await?);
+        let mut jumps = jump.jumps;
+        jumps.push(jump.session);
+
+        Ok(Self { session, jumps, sftp: None, transfer_options: TransferOptions::default(), cancellation: None })
+    }
+
+    /// Creates a session on the same connection for another command, with its own SFTP subsystem and no cancellation.
+    /// The commands of the sessions run concurrently over their own channels.
+    pub fn share(&self) -> Session {
+        Self {
+            session: self.session.clone(),
+            jumps: self.jumps.clone(),
+            sftp: None,
+            transfer_options: self.transfer_options,
+            cancellation: None,
+        }
+    }
+
+    /// Gets the connection to authenticate, which is done before the session is shared.
+    fn auth_handle(&mut self) -> &mut Handle<Client> {
+        Arc::get_mut(&mut self.session).expect("Session is authenticated before it is shared")
+    }
+
+    fn config() -> Arc<client::Config> {
         let config = client::Config {
             inactivity_timeout: Some(Duration::from_secs(60 * 5)),
+            keepalive_interval: Some(Duration::from_secs(30)),
+            keepalive_max: 3,
             ..<_>::default()
         };
 
-        let config = Arc::new(config);
-        let mut session = client::connect(config, address, Client).await?;
-        let auth_result = session.authenticate_password(username, password).await?;
+        Arc::new(config)
+    }
+
+    /// Authenticates with a password.
+    pub async fn authenticate_password(&mut self, username: impl Into<String>, password: impl Into<String>) -> Result<(), Error> {
+        let auth_result = self.auth_handle().authenticate_password(username, password).await?;
         if !auth_result {
             return Err(Error::AuthenticationFailed);
         }
 
-        Ok(Self { session })
+        Ok(())
+    }
+
+    /// Authenticates with keyboard-interactive, the prompts of each round are answered by `answer`
+    /// with the name, the instructions and the prompts of the round.
+    pub async fn authenticate_keyboard_interactive<F, Fut>(&mut self, username: impl Into<String>, mut answer: F) -> Result<(), Error>
+    where
+        F: FnMut(String, String, Vec<Prompt>) -> Fut,
+        Fut: Future<Output=Result<Vec<String>, Error>>,
+    {
+        let mut response = self.auth_handle().authenticate_keyboard_interactive_start(username, None::<String>).await?;
+        loop {
+            match response {
+                KeyboardInteractiveAuthResponse::Success => return Ok(()),
+                KeyboardInteractiveAuthResponse::Failure => return Err(Error::AuthenticationFailed),
+                KeyboardInteractiveAuthResponse::InfoRequest { name, instructions, prompts } => {
+                    let answers = answer(name, instructions, prompts).await?;
+                    response = self.auth_handle().authenticate_keyboard_interactive_respond(answers).await?;
+                }
+            }
+        }
+    }
+
+    /// Authenticates with a private key file, which may be protected by a passphrase.
+    pub async fn authenticate_private_key(&mut self, username: impl Into<String>, private_key_file: &str, passphrase: Option<&str>) -> Result<(), Error> {
+        let key_pair = keys::load_secret_key(private_key_file, passphrase).map_err(|e| match e {
+            keys::Error::KeyIsEncrypted => Error::InvalidPassphrase,
+            keys::Error::KeyIsCorrupt if passphrase.is_some() => Error::InvalidPassphrase,
+            e => Error::InvalidPrivateKey(e.to_string()),
+        })?;
+
+        let auth_result = self.auth_handle().authenticate_publickey(username, Arc::new(key_pair)).await?;
+        if !auth_result {
+            return Err(Error::PrivateKeyRejected);
+        }
+
+        Ok(())
+    }
+
+    /// Authenticates with the identities of a local ssh-agent.
+    #[cfg(unix)]
+    pub async fn authenticate_agent(&mut self, username: impl Into<String>, socket: Option<&str>) -> Result<(), Error> {
+        let agent = match socket {
+            Some(socket) => AgentClient::connect_uds(socket).await,
+            None => AgentClient::connect_env().await,
+        };
+        let agent = agent.map_err(|e| Error::AgentUnavailable(e.to_string()))?;
+        self.authenticate_with_agent(username, agent).await
+    }
+
+    /// Authenticates with the identities of the Windows OpenSSH agent, listening on the named pipe
+    /// `OPENSSH_AGENT_PIPE` unless another pipe is specified.
+    #[cfg(windows)]
+    pub async fn authenticate_agent(&mut self, username: impl Into<String>, socket: Option<&str>) -> Result<(), Error> {
+        use tokio::net::windows::named_pipe::ClientOptions;
+
+        // ERROR_PIPE_BUSY, all instances of the pipe are in use.
+        const ERROR_PIPE_BUSY: i32 = 231;
+        let pipe = socket.unwrap_or(OPENSSH_AGENT_PIPE);
+        let mut attempts = 0;
+        let stream = loop {
+            match ClientOptions::new().open(pipe) {
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && attempts < 10 => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                result => break result.map_err(|e| Error::AgentUnavailable(e.to_string()))?,
+            }
+        };
+        self.authenticate_with_agent(username, AgentClient::connect(stream)).await
+    }
+
+    /// Authenticates with the identities of a local ssh-agent.
+    #[cfg(not(any(unix, windows)))]
+    pub async fn authenticate_agent(&mut self, _username: impl Into<String>, _socket: Option<&str>) -> Result<(), Error> {
+        Err(Error::AgentUnavailable("当前系统不支持 ssh-agent".into()))
+    }
+
+    /// Authenticates with the identities offered by the connected ssh-agent, trying them in turn.
+    #[cfg(any(unix, windows))]
+    async fn authenticate_with_agent<S>(&mut self, username: impl Into<String>, mut agent: AgentClient<S>) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let identities = agent.request_identities().await.map_err(|e| Error::AgentUnavailable(e.to_string()))?;
+
+        let username = username.into();
+        for key in identities {
+            let (returned_agent, auth_result) = self.auth_handle().authenticate_future(username.as_str(), key, agent).await;
+            agent = returned_agent;
+            if auth_result.map_err(|e| Error::AgentUnavailable(format!("{:?}", e)))? {
+                return Ok(());
+            }
+        }
+
+        Err(Error::AuthenticationFailed)
     }
 
     /// Executes a command on the remote server.
@@ -52,7 +252,7 @@ impl Session {
         let mut buffer = Vec::new();
 
         loop {
-            let Some(message) = channel.wait().await else {
+            let Some(message) = wait_message(&mut channel, self.cancellation.as_ref()).await? else {
                 break;
             };
 
@@ -85,13 +285,22 @@ impl Session {
 
     /// Executes a command on the remote server and streams the output to the window.
     pub async fn execute_command_stream(&mut self, command: &str, window: &Window) -> Result<(), Error> {
+        self.run_command_stream(command, false, window).await?.check()
+    }
+
+    /// Runs a command on the remote server and streams the output to the window,
+    /// returning the exit code and the captured output without checking them.
+    /// Only the last `STDERR_TAIL_SIZE` bytes of the stderr are kept, and the last `STDOUT_TAIL_SIZE` bytes
+    /// of the stdout unless `capture_stdout` is set.
+    pub async fn run_command_stream(&mut self, command: &str, capture_stdout: bool, window: &Window) -> Result<CommandOutput, Error> {
         let mut channel = self.session.channel_open_session().await?;
         channel.exec(true, command).await?;
-        let mut code = None;
-        let mut error = None;
+        let mut output = CommandOutput::default();
+        // The stdout is decoded once complete, a character may be split across data messages.
+        let mut stdout = vec![];
 
         loop {
-            let Some(message) = channel.wait().await else {
+            let Some(message) = wait_message(&mut channel, self.cancellation.as_ref()).await? else {
                 break;
             };
 
@@ -100,28 +309,27 @@ impl Session {
                     window.emit("command-result", PartialResult {
                         data: String::from_utf8_lossy(data).as_ref()
                     })?;
+                    if capture_stdout {
+                        stdout.extend_from_slice(data);
+                    } else {
+                        push_bytes_tail(&mut stdout, data, STDOUT_TAIL_SIZE);
+                    }
                 }
                 ChannelMsg::ExitStatus { exit_status } => {
-                    code = Some(exit_status);
+                    output.exit_code = Some(exit_status);
                 }
                 ChannelMsg::ExitSignal { error_message, .. } => {
-                    error = Some(error_message);
+                    push_tail(&mut output.stderr, &error_message, STDERR_TAIL_SIZE);
                 }
                 ChannelMsg::ExtendedData { ref data, .. } => {
-                    let error_message = String::from_utf8_lossy(data).to_string();
-                    error = Some(error_message);
+                    push_tail(&mut output.stderr, &String::from_utf8_lossy(data), STDERR_TAIL_SIZE);
                 }
                 _ => {}
             }
         }
+        output.stdout = String::from_utf8_lossy(&stdout).into_owned();
 
-        code.ok_or(Error::CommandExecutionTimeout).and_then(|code| {
-            if code == 0 {
-                Ok(())
-            } else {
-                Err(Error::CommandExecutionFailed(error.unwrap_or("未知错误".into())))
-            }
-        })
+        Ok(output)
     }
 
     /// Tests a command on the remote server.
@@ -131,7 +339,7 @@ impl Session {
         let mut code = None;
 
         loop {
-            let Some(message) = channel.wait().await else {
+            let Some(message) = wait_message(&mut channel, self.cancellation.as_ref()).await? else {
                 break;
             };
 
@@ -146,22 +354,524 @@ impl Session {
         code.ok_or(Error::CommandExecutionTimeout).map(|code| code == 0)
     }
 
-    pub async fn transfer_file(&mut self, dir: &str, filename: &str, data: &[u8], window: &Window) -> Result<(), Error> {
-        let _ = self.execute_command(format!("mkdir -p {dir}").as_ref()).await?;
-        let channel = self.session.channel_open_session().await?;
-        channel.request_subsystem(true, "sftp").await?;
-        let mut sftp = SftpSession::new(channel.into_stream()).await?;
+    /// Sets the cancellation token of the run using the session, `None` once the run is over.
+    pub fn set_cancellation(&mut self, cancellation: Option<CancellationToken>) {
+        self.cancellation = cancellation;
+    }
+
+    /// Gets the cancellation token of the run using the session.
+    pub fn cancellation(&self) -> Option<CancellationToken> {
+        self.cancellation.clone()
+    }
+
+    /// Sets the tuning of SFTP uploads.
+    pub fn set_transfer_options(&mut self, transfer_options: TransferOptions) {
+        self.transfer_options = transfer_options;
+    }
+
+    /// Reads the remote file as UTF-8 text, `None` if the file does not exist.
+    pub async fn read_file_to_string(&mut self, file: &str) -> Result<Option<String>, Error> {
+        let sftp = self.sftp().await?;
+        let result = async {
+            if !sftp.try_exists(file).await? {
+                return Ok(None);
+            }
+
+            let content = sftp.read(file).await?;
+            Ok::<_, Error>(Some(String::from_utf8_lossy(&content).into_owned()))
+        }.await;
+        self.check_sftp(result)
+    }
+
+    /// Checks whether the remote file or directory exists.
+    pub async fn file_exists(&mut self, file: &str) -> Result<bool, Error> {
+        let result = self.sftp().await?.try_exists(file).await.map_err(Error::from);
+        self.check_sftp(result)
+    }
+
+    /// Computes the SHA-256 digest of the remote file, `None` if the file does not exist or cannot be read.
+    pub async fn sha256sum(&mut self, file: &str) -> Result<Option<String>, Error> {
+        let result = self.execute_command(&format!("sha256sum {} | cut -d ' ' -f 1", shell_quote(file))).await;
+        match result {
+            Ok(digest) if !digest.trim().is_empty() => Ok(Some(digest.trim().to_owned())),
+            Ok(_) | Err(Error::CommandExecutionFailed(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the SFTP subsystem, requesting it on first use.
+    async fn sftp(&mut self) -> Result<&mut SftpSession, Error> {
+        if self.sftp.is_none() {
+            let channel = self.session.channel_open_session().await?;
+            channel.request_subsystem(true, "sftp").await?;
+            self.sftp = Some(SftpSession::new(channel.into_stream()).await?);
+        }
+
+        Ok(self.sftp.as_mut().unwrap())
+    }
+
+    /// Drops the SFTP subsystem if the operation failed because its channel is gone or requests were abandoned,
+    /// so the next operation requests a new one. Errors reported by the server keep the subsystem.
+    fn check_sftp<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(ref e) = result {
+            if !matches!(e, Error::SftpError(SftpError::Status(_) | SftpError::Limited(_))) {
+                self.sftp = None;
+            }
+        }
+        result
+    }
+
+    /// Creates the remote directory and all of its missing parents.
+    pub async fn create_dir_all(&mut self, dir: &str) -> Result<(), Error> {
+        let sftp = self.sftp().await?;
+        let result = async {
+            let mut current = if dir.starts_with('/') { String::from("/") } else { String::new() };
+            for part in dir.split('/').filter(|part| !part.is_empty()) {
+                if !current.is_empty() && !current.ends_with('/') {
+                    current.push('/');
+                }
+                current.push_str(part);
+
+                if !sftp.try_exists(current.as_str()).await? {
+                    sftp.create_dir(current.as_str()).await?;
+                }
+            }
+
+            Ok::<_, Error>(())
+        }.await;
+        self.check_sftp(result)
+    }
+
+    /// Transfers the data read from the source to the remote directory.
+    /// The data is uploaded to a `.part` file first, which is renamed into place once complete,
+    /// an existing `.part` file of an interrupted upload is resumed if its tail matches the source.
+    /// The permissions are applied to the `.part` file, so the file is never in place with other permissions,
+    /// the mode and ownership not given are kept from the file it replaces.
+    /// If the SHA-256 `checksum` is given, the `.part` file is only renamed into place if it matches,
+    /// otherwise it is removed so the next transfer starts over.
+    pub async fn transfer_file<R: AsyncRead + AsyncSeek + Unpin + Send>(&mut self, dir: &str, filename: &str, source: R, total_size: u64, checksum: Option<&str>, permissions: &FilePermissions, window: &Window) -> Result<(), Error> {
+        let result = self.upload_part_file(dir, filename, source, total_size, true, window).await;
+        let (file, part_file) = self.check_sftp(result)?;
+        if let Some(checksum) = checksum {
+            let part_checksum = self.sha256sum(&part_file).await?.unwrap_or_default();
+            if part_checksum != checksum {
+                let result = self.sftp().await?.remove_file(part_file.as_str()).await.map_err(Error::from);
+                self.check_sftp(result)?;
+                return Err(Error::ChecksumMismatch(file, checksum.to_owned(), part_checksum));
+            }
+        }
+        self.inherit_permissions(&part_file, &file).await?;
+        self.apply_permissions(&part_file, permissions).await?;
+        self.rename_file(&part_file, &file).await
+    }
+
+    /// Replaces the remote file like `transfer_file`, keeping a timestamped backup of the previous file.
+    /// The data is uploaded to a `.replace` file, which is never resumed, instead of the `.part` file of uploads.
+    /// Returns the backup file, `None` if there was no previous file.
+    pub async fn replace_file<R: AsyncRead + AsyncSeek + Unpin + Send>(&mut self, dir: &str, filename: &str, source: R, total_size: u64, permissions: &FilePermissions, window: &Window) -> Result<Option<String>, Error> {
+        let result = self.upload_part_file(dir, filename, source, total_size, false, window).await;
+        let (file, part_file) = self.check_sftp(result)?;
+        self.inherit_permissions(&part_file, &file).await?;
+        self.apply_permissions(&part_file, permissions).await?;
+        let backup_file = self.backup_file(&file).await?;
+        self.rename_file(&part_file, &file).await?;
+        Ok(backup_file)
+    }
+
+    /// Copies the remote file to a sibling `<file>.bak.<timestamp>` file, `None` if the file does not exist.
+    /// An existing backup is never overwritten, further backups in the same second get a `-<n>` suffix.
+    pub async fn backup_file(&mut self, file: &str) -> Result<Option<String>, Error> {
+        if !self.file_exists(file).await? {
+            return Ok(None);
+        }
+
+        let file = shell_quote(file);
+        let backup_file = self.execute_command(&format!(
+            "stamp=$(date +%Y%m%d%H%M%S) && backup={file}.bak.$stamp && n=1 && \
+            while [ -e \"$backup\" ]; do backup={file}.bak.$stamp-$n; n=$((n + 1)); done && \
+            cp -p {file} \"$backup\" && echo \"$backup\"",
+        )).await?;
+        Ok(Some(backup_file.trim().to_owned()))
+    }
+
+    /// Lists the backups of the remote file, newest first.
+    pub async fn list_backups(&mut self, file: &str) -> Result<Vec<FileBackup>, Error> {
+        let output = self.execute_command(&format!("ls -1d {}.bak.* 2>/dev/null || true", shell_quote(file))).await?;
+        let prefix = format!("{file}.bak.");
+        let mut backups: Vec<FileBackup> = output.lines()
+            .filter_map(|line| line.strip_prefix(&prefix).map(|timestamp| FileBackup {
+                file: line.to_owned(),
+                timestamp: timestamp.to_owned(),
+            }))
+            .collect();
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(backups)
+    }
+
+    /// Restores the backup of the remote file atomically, the current file is backed up before.
+    pub async fn restore_backup(&mut self, file: &str, backup_file: &str) -> Result<(), Error> {
+        let is_backup = backup_file.strip_prefix(&format!("{file}.bak."))
+            .is_some_and(|timestamp| !timestamp.is_empty() && !timestamp.contains('/'));
+        if !is_backup {
+            return Err(Error::InvalidPath);
+        }
+
+        self.backup_file(file).await?;
+        let part_file = format!("{file}.replace");
+        self.execute_command(&format!("cp -p {} {}", shell_quote(backup_file), shell_quote(&part_file))).await?;
+        self.rename_file(&part_file, file).await
+    }
+
+    /// Renames the remote file atomically, replacing the target file if it exists.
+    async fn rename_file(&mut self, file: &str, target_file: &str) -> Result<(), Error> {
+        self.execute_command(&format!("mv -f {} {}", shell_quote(file), shell_quote(target_file))).await?;
+        Ok(())
+    }
+
+    /// Uploads the data to the `.part` file of the remote file, resuming an interrupted upload if `resume` is set,
+    /// otherwise to its `.replace` file from the start.
+    /// Returns the remote file and the file the data is uploaded to.
+    async fn upload_part_file<R: AsyncRead + AsyncSeek + Unpin + Send>(&mut self, dir: &str, filename: &str, mut source: R, total_size: u64, resume: bool, window: &Window) -> Result<(String, String), Error> {
+        let dir = PathBuf::from(dir).to_slash().ok_or(Error::InvalidPath)?.into_owned();
+        self.create_dir_all(&dir).await?;
         let mut buf = PathBuf::new();
-        buf.push(dir);
+        buf.push(&dir);
         buf.push(filename);
+        let file = buf.to_slash().ok_or(Error::InvalidPath)?.into_owned();
+        let (part_file, offset) = if resume {
+            let part_file = format!("{file}.part");
+            let offset = self.resume_offset(&part_file, &mut source, total_size).await?;
+            (part_file, offset)
+        } else {
+            (format!("{file}.replace"), 0)
+        };
+        if offset > 0 {
+            println!("Resume transfer of {} from offset {}", file, offset);
+        }
+        source.seek(SeekFrom::Start(offset)).await?;
+
+        let transfer_options = self.transfer_options;
+        let cancellation = self.cancellation.clone();
+        let sftp = self.sftp().await?;
+        let mut file_transfer = FileTransfer::new_with_event_progress_reporter(sftp, window)
+            .with_options(transfer_options)
+            .with_cancellation(cancellation);
+        file_transfer.transfer_file(&part_file, source, offset, total_size).await?;
+
+        Ok((file, part_file))
+    }
+
+    /// Transfers the local directory tree to the remote directory, keeping the relative structure and the file modes.
+    /// The file modes are replaced by the mode of the permissions if given, the modification times are kept
+    /// if `preserve_mtime` is set. The ownership is applied to the transferred files and directories only,
+    /// the remote directory itself and its other entries are left unchanged.
+    /// Symbolic links are followed, a broken or circular link fails the transfer.
+    pub async fn transfer_directory(&mut self, local_dir: &Path, remote_dir: &str, permissions: &FilePermissions, preserve_mtime: bool, window: &Window) -> Result<(), Error> {
+        let remote_dir = PathBuf::from(remote_dir).to_slash().ok_or(Error::InvalidPath)?.into_owned();
+        let mut dirs = vec![];
+        let mut pending = vec![(fs::canonicalize(local_dir).await?, remote_dir, vec![])];
+        while let Some((local_dir, remote_dir, mut ancestors)) = pending.pop() {
+            self.create_dir_all(&remote_dir).await?;
+            ancestors.push(local_dir.clone());
+
+            let mut entries = fs::read_dir(&local_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = fs::metadata(entry.path()).await?;
+                let filename = entry.file_name().to_str().ok_or(Error::InvalidPath)?.to_owned();
+                let remote_path = format!("{}/{}", remote_dir.trim_end_matches('/'), filename);
+                if metadata.is_dir() {
+                    let path = fs::canonicalize(entry.path()).await?;
+                    if ancestors.contains(&path) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Circular symbolic link: {:?}", entry.path())).into());
+                    }
+                    pending.push((path, remote_path, ancestors.clone()));
+                } else if metadata.is_file() {
+                    println!("Transfer file from {:?} to {}", entry.path(), remote_path);
+                    let file = fs::File::open(entry.path()).await?;
+                    let permissions = FilePermissions { mode: permissions.mode.or_else(|| local_file_mode(&metadata)), ..permissions.clone() };
+                    self.transfer_file(&remote_dir, &filename, file, metadata.len(), None, &permissions, window).await?;
+                    self.set_file_attributes(&remote_path, None, file_mtime(&metadata, preserve_mtime)).await?;
+                }
+            }
+
+            let metadata = fs::metadata(&local_dir).await?;
+            dirs.push((remote_dir, local_file_mode(&metadata), file_mtime(&metadata, preserve_mtime)));
+        }
+
+        // Directory attributes are applied last and deepest first, so a read-only mode or a new mtime
+        // is not disturbed by writing the entries of the directory. The remote directory itself comes first.
+        for (index, (remote_dir, mode, mtime)) in dirs.into_iter().enumerate().rev() {
+            if index > 0 {
+                self.chown(&remote_dir, permissions.owner.as_deref(), permissions.group.as_deref()).await?;
+            }
+            self.set_file_attributes(&remote_dir, mode, mtime).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the permission bits and the modification time of the remote file, absent values are left unchanged.
+    pub async fn set_file_attributes(&mut self, file: &str, mode: Option<u32>, mtime: Option<u32>) -> Result<(), Error> {
+        if mode.is_none() && mtime.is_none() {
+            return Ok(());
+        }
+
+        let mut attributes = FileAttributes::empty();
+        attributes.permissions = mode;
+        attributes.mtime = mtime;
+        attributes.atime = mtime;
+        let result = self.sftp().await?.set_metadata(file, attributes).await.map_err(Error::from);
+        self.check_sftp(result)
+    }
+
+    /// Copies the mode and ownership of the existing target file to the remote file which replaces it,
+    /// as overwriting the target in place kept them. Nothing is done if the target does not exist.
+    async fn inherit_permissions(&mut self, file: &str, target_file: &str) -> Result<(), Error> {
+        let sftp = self.sftp().await?;
+        let result = async {
+            if !sftp.try_exists(target_file).await? {
+                return Ok(());
+            }
+
+            let target = sftp.metadata(target_file).await?;
+            let current = sftp.metadata(file).await?;
+            let mut attributes = FileAttributes::empty();
+            attributes.permissions = target.permissions.map(|mode| mode & 0o7777);
+            // Only changed ownership is set, which requires the privilege to chown.
+            if (target.uid, target.gid) != (current.uid, current.gid) {
+                attributes.uid = target.uid;
+                attributes.gid = target.gid;
+            }
+            sftp.set_metadata(file, attributes).await?;
+
+            Ok::<_, Error>(())
+        }.await;
+        self.check_sftp(result)
+    }
+
+    /// Applies the mode and ownership to the remote file.
+    pub async fn apply_permissions(&mut self, file: &str, permissions: &FilePermissions) -> Result<(), Error> {
+        self.set_file_attributes(file, permissions.mode, None).await?;
+        self.chown(file, permissions.owner.as_deref(), permissions.group.as_deref()).await
+    }
+
+    /// Changes the owner and/or group of the remote file, nothing is done if neither is given.
+    pub async fn chown(&mut self, file: &str, owner: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+        let spec = match (owner, group) {
+            (Some(owner), Some(group)) => format!("{owner}:{group}"),
+            (Some(owner), None) => owner.to_owned(),
+            (None, Some(group)) => format!(":{group}"),
+            (None, None) => return Ok(()),
+        };
+
+        self.execute_command(&format!("chown {} {}", shell_quote(&spec), shell_quote(file))).await?;
+        Ok(())
+    }
+
+    /// Downloads the remote file to the local file, creating its parent directories.
+    /// The data is downloaded to a local `.part` file first, which is renamed into place once complete
+    /// and removed if the download fails.
+    pub async fn download_file(&mut self, file: &str, local_file: &Path, window: &Window) -> Result<(), Error> {
+        if let Some(parent) = local_file.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut part_file = local_file.as_os_str().to_owned();
+        part_file.push(".part");
+        let part_file = PathBuf::from(part_file);
+        let transfer_options = self.transfer_options;
+        let cancellation = self.cancellation.clone();
+        let sftp = self.sftp().await?;
+        let target = fs::File::create(&part_file).await?;
+        let result = FileTransfer::new_with_event_progress_reporter(sftp, window)
+            .with_options(transfer_options)
+            .with_cancellation(cancellation)
+            .download_file(file, target).await;
+        if let Err(e) = self.check_sftp(result) {
+            let _ = fs::remove_file(&part_file).await;
+            return Err(e);
+        }
+
+        fs::rename(&part_file, local_file).await?;
+        Ok(())
+    }
+
+    /// Gets the offset to resume an upload from, which is the size of the remote partial file
+    /// if the hash of its tail matches the same range of the source, zero otherwise.
+    async fn resume_offset<R: AsyncRead + AsyncSeek + Unpin + Send>(&mut self, part_file: &str, source: &mut R, total_size: u64) -> Result<u64, Error> {
+        let sftp = self.sftp().await?;
+        if !sftp.try_exists(part_file).await? {
+            return Ok(0);
+        }
+
+        let offset = sftp.metadata(part_file).await?.len();
+        if offset == 0 || offset > total_size {
+            return Ok(0);
+        }
+
+        let tail_size = cmp::min(offset, RESUME_TAIL_SIZE);
+        let remote_digest = self.execute_command(
+            &format!("tail -c {tail_size} {} | sha256sum | cut -d ' ' -f 1", shell_quote(part_file)),
+        ).await.unwrap_or_default();
+        source.seek(SeekFrom::Start(offset - tail_size)).await?;
+        let local_digest = sha256_digest((&mut *source).take(tail_size)).await?;
+
+        Ok(if remote_digest.trim() == local_digest { offset } else { 0 })
+    }
 
-        let mut file_transfer = FileTransfer::new_with_event_progress_reporter(&mut sftp, window);
-        file_transfer.transfer_file(buf.to_slash().unwrap().as_ref(), data).await
+    /// Checks whether the connection to the server or one of the jump hosts is closed.
+    pub fn is_closed(&self) -> bool {
+        self.session.is_closed() || self.jumps.iter().any(|jump| jump.is_closed())
     }
 
-    /// Closes the session.
+    /// Closes the session and the jump host sessions it is tunneled through, which ends the sessions sharing them.
     pub async fn close(&mut self) -> Result<(), Error> {
+        self.sftp = None;
         self.session.disconnect(Disconnect::ByApplication, "", "English").await?;
+        for jump in self.jumps.iter().rev() {
+            jump.disconnect(Disconnect::ByApplication, "", "English").await?;
+        }
         Ok(())
     }
 }
+
+/// Waits for the next message of the channel.
+/// If the run is cancelled meanwhile, the remote command is terminated and the channel is closed.
+async fn wait_message(channel: &mut Channel<client::Msg>, cancellation: Option<&CancellationToken>) -> Result<Option<ChannelMsg>, Error> {
+    let Some(cancellation) = cancellation else {
+        return Ok(channel.wait().await);
+    };
+
+    tokio::select! {
+        message = channel.wait() => Ok(message),
+        _ = cancellation.cancelled() => {
+            let _ = channel.signal(Sig::TERM).await;
+            let _ = channel.close().await;
+            Err(Error::Cancelled)
+        }
+    }
+}
+
+/// Appends the output to the buffer, keeping only the last `limit` bytes of the buffer.
+fn push_tail(buffer: &mut String, output: &str, limit: usize) {
+    buffer.push_str(output);
+    if buffer.len() > limit {
+        let mut start = buffer.len() - limit;
+        while !buffer.is_char_boundary(start) {
+            start += 1;
+        }
+        buffer.drain(..start);
+    }
+}
+
+/// Appends the data to the buffer, keeping only the last `limit` bytes of the buffer
+/// without a partial UTF-8 character at its start.
+fn push_bytes_tail(buffer: &mut Vec<u8>, data: &[u8], limit: usize) {
+    buffer.extend_from_slice(data);
+    if buffer.len() > limit {
+        let mut start = buffer.len() - limit;
+        while buffer.get(start).is_some_and(|byte| byte & 0xC0 == 0x80) {
+            start += 1;
+        }
+        buffer.drain(..start);
+    }
+}
+
+/// Gets the permission bits of the local file, which are only available on unix.
+#[cfg(unix)]
+fn local_file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Gets the permission bits of the local file, which are only available on unix.
+#[cfg(not(unix))]
+fn local_file_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+fn file_mtime(metadata: &Metadata, preserve_mtime: bool) -> Option<u32> {
+    if !preserve_mtime {
+        return None;
+    }
+
+    metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as u32)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::known_hosts::KnownHosts;
+
+    /// Connects to the SSH server at `VEF_TEST_SSH_HOST` and `VEF_TEST_SSH_PORT`, a local OpenSSH server by default,
+    /// pinning its key into a temporary known hosts store.
+    pub(crate) async fn connect_test_server() -> Session {
+        let host = env::var("VEF_TEST_SSH_HOST").unwrap_or("127.0.0.1".into());
+        let port = env::var("VEF_TEST_SSH_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(22);
+        let known_hosts = KnownHosts::load(env::temp_dir().join(format!("vef-test-known-hosts-{}.json", std::process::id())))
+            .expect("Failed to load the test known hosts");
+        let client = Client::new("test", host.as_str(), port, known_hosts);
+        Session::connect((host.as_str(), port), client).await.expect("Failed to connect to the test server")
+    }
+
+    /// Gets the user and password of the test server from `VEF_TEST_SSH_USER` and `VEF_TEST_SSH_PASSWORD`.
+    pub(crate) fn test_credentials() -> (String, String) {
+        let username = env::var("VEF_TEST_SSH_USER").expect("VEF_TEST_SSH_USER is not set");
+        let password = env::var("VEF_TEST_SSH_PASSWORD").expect("VEF_TEST_SSH_PASSWORD is not set");
+        (username, password)
+    }
+
+    /// Authenticates the test session with the test credentials, by password or keyboard-interactive.
+    pub(crate) async fn authenticate_test_session(session: &mut Session) {
+        let (username, password) = test_credentials();
+        if session.authenticate_password(username.as_str(), password.as_str()).await.is_ok() {
+            return;
+        }
+
+        session.authenticate_keyboard_interactive(username.as_str(), |_, _, prompts| {
+            let answers: Vec<String> = prompts.iter().map(|_| password.clone()).collect();
+            async move { Ok(answers) }
+        }).await.expect("Failed to authenticate to the test server");
+    }
+
+    /// Gets the SFTP subsystem of the test session.
+    pub(crate) async fn test_sftp(session: &mut Session) -> &mut SftpSession {
+        session.sftp().await.expect("Failed to request the SFTP subsystem")
+    }
+
+    /// Needs an OpenSSH server which only accepts keyboard-interactive, i.e. with `PasswordAuthentication no`,
+    /// `KbdInteractiveAuthentication yes` and `UsePAM yes` in its `sshd_config`:
+    /// `VEF_TEST_SSH_USER=... VEF_TEST_SSH_PASSWORD=... cargo test keyboard_interactive -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn authenticates_keyboard_interactive_only_server() {
+        let (username, password) = test_credentials();
+        let mut session = connect_test_server().await;
+        let rejected = session.authenticate_password(username.as_str(), password.as_str()).await;
+        assert!(matches!(rejected, Err(Error::AuthenticationFailed)));
+
+        let mut rounds = 0;
+        session.authenticate_keyboard_interactive(username.as_str(), |_, _, prompts| {
+            rounds += 1;
+            let answers: Vec<String> = prompts.iter().map(|_| password.clone()).collect();
+            async move { Ok(answers) }
+        }).await.expect("Keyboard-interactive authentication failed");
+
+        assert!(rounds > 0);
+        assert_eq!(session.execute_command("echo ok").await.unwrap().trim(), "ok");
+    }
+
+    #[test]
+    fn keeps_tail_without_partial_character() {
+        let mut buffer = vec![];
+        push_bytes_tail(&mut buffer, "ab".as_bytes(), 4);
+        push_bytes_tail(&mut buffer, "中文".as_bytes(), 4);
+        assert_eq!(String::from_utf8(buffer).unwrap(), "文");
+    }
+}
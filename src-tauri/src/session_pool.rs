@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::error::Error;
+use crate::server_settings::ServerSettings;
+use crate::session::Session;
+
+/// The time to wait for a pooled session to disconnect when the app exits.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct PooledSession {
+    /// The serialized server settings the session was created with, a change of them requires a new session.
+    settings_key: String,
+    session: Session,
+}
+
+/// A pool of authenticated connections keyed by server id, shared by the commands to the same server.
+#[derive(Default)]
+pub struct SessionPool {
+    sessions: Mutex<HashMap<String, Arc<Mutex<Option<PooledSession>>>>>,
+}
+
+impl SessionPool {
+    /// Acquires a session on the pooled connection of the server, connecting a new one if there is none,
+    /// the connection died or the server settings changed.
+    /// The pool entry is only locked to check the connection or to connect, the commands using the
+    /// acquired sessions run concurrently.
+    pub async fn acquire<F, Fut>(&self, server_settings: &ServerSettings, connect: F) -> Result<Session, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output=Result<Session, Error>>,
+    {
+        let entry = self.sessions.lock().await
+            .entry(server_settings.id.clone())
+            .or_default()
+            .clone();
+        let mut pooled = entry.lock().await;
+        let settings_key = serde_json::to_string(server_settings).map_err(std::io::Error::from)?;
+
+        if let Some(ref pooled) = *pooled {
+            if pooled.settings_key == settings_key && !pooled.session.is_closed() {
+                return Ok(pooled.session.share());
+            }
+        }
+
+        // A replaced connection is not closed, the commands still using it keep running and it ends with them.
+        println!("Connecting to server: {}", server_settings.id);
+        let session = connect().await?;
+        let shared = session.share();
+        *pooled = Some(PooledSession { settings_key, session });
+
+        Ok(shared)
+    }
+
+    /// Closes all pooled sessions, the commands still using them fail.
+    /// A session which is still connecting is abandoned instead of waiting for it.
+    pub async fn close_all(&self) {
+        let entries: Vec<_> = self.sessions.lock().await.drain().map(|(_, entry)| entry).collect();
+        for entry in entries {
+            let Ok(mut pooled) = entry.try_lock() else {
+                continue;
+            };
+
+            if let Some(mut pooled) = pooled.take() {
+                if timeout(CLOSE_TIMEOUT, pooled.session.close()).await.is_err() {
+                    println!("Timed out closing session");
+                }
+            }
+        }
+    }
+}
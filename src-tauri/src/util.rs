@@ -1,6 +1,9 @@
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use sha2::{Digest, Sha256};
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::error::Error;
 
@@ -11,4 +14,29 @@ pub async fn copy_file(file: &str, target_file: &str) -> Result<(), Error> {
 
     fs::copy(file, target_file).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Computes the hex encoded SHA-256 digest of all data read from the reader.
+pub async fn sha256_digest<R: AsyncRead + Unpin>(mut reader: R) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Gets the current time in milliseconds since the unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Quotes the value to be used as a single shell word.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}